@@ -2,42 +2,105 @@ use crate::error::Error;
 use std::io::BufRead;
 use std::io::Bytes;
 use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
 
+// A file pulled in with ~, shared by every chunk read from it
 #[derive(Debug, PartialEq, Clone)]
+pub struct Include {
+    pub path: PathBuf,
+    // The files that included this one, outermost first, with the chunks that included them
+    pub included_from: Vec<(PathBuf, Chunk)>,
+}
+
+// A macro invocation, shared by every chunk of the body it was replaced with
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expansion {
+    pub name: String,
+    pub chunk: Chunk,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Chunk {
     pub value: String,
     pub line: usize,
+    // Counted in characters from the start of the line, for pointing at the chunk on screen
     pub column: usize,
+    // Counted in bytes from the start of the file
+    pub offset: usize,
+    // The included file this chunk was read from, None for the file being assembled
+    pub include: Option<Rc<Include>>,
+    // The macro invocation this chunk was expanded from, if any
+    pub expansion: Option<Rc<Expansion>>,
 }
 
 impl Chunk {
-    pub fn new(value: String, line: usize, column: usize) -> Chunk {
+    pub fn new(value: String, line: usize, column: usize, offset: usize) -> Chunk {
         Chunk {
             value,
             line,
             column,
+            offset,
+            include: None,
+            expansion: None,
+        }
+    }
+
+    // The file this chunk was read from, given the file being assembled
+    pub fn file<'a>(&'a self, main: &'a Path) -> &'a Path {
+        match &self.include {
+            Some(include) => &include.path,
+            None => main,
         }
     }
 }
 
 pub struct Chunker<'a> {
+    file: PathBuf,
     bytes: Bytes<&'a mut dyn BufRead>,
     line: usize,
     column: usize,
+    offset: usize,
+    done: bool,
 }
 
 impl Chunker<'_> {
-    pub fn new(reader: &mut dyn BufRead) -> Chunker {
+    pub fn new(file: PathBuf, reader: &mut dyn BufRead) -> Chunker<'_> {
         Chunker {
+            file,
             bytes: reader.bytes(),
             line: 0,
             column: 0,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    // Turns the bytes of a chunk that started at column and offset into a chunk
+    fn chunk(&self, bytes: Vec<u8>, column: usize, offset: usize) -> Result<Chunk, Error> {
+        match String::from_utf8(bytes) {
+            Ok(value) => Ok(Chunk::new(value, self.line, column, offset)),
+            Err(err) => {
+                let value = String::from_utf8_lossy(err.as_bytes()).to_string();
+                let chunk = Chunk::new(value, self.line, column, offset);
+                Err(
+                    Error::new("invalid UTF-8".to_string(), chunk, self.file.clone())
+                        .with_code("E0001"),
+                )
+            }
         }
     }
 }
 
-fn is_whitespace(byte: u8) -> bool {
-    matches!(byte, b' ' | b'\n' | b'\t')
+// \r is whitespace so that files with Windows line endings work, and \x0c is a form feed
+pub(crate) fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\n' | b'\t' | b'\r' | b'\x0c')
+}
+
+// The bytes after the first in a multi-byte UTF-8 character look like 0b10xxxxxx
+fn starts_character(byte: u8) -> bool {
+    byte & 0xc0 != 0x80
 }
 
 impl Iterator for Chunker<'_> {
@@ -45,52 +108,55 @@ impl Iterator for Chunker<'_> {
 
     fn next(&mut self) -> Option<Self::Item> {
         let mut s: Vec<u8> = vec![];
+        // Where the current chunk started
+        let mut column = 0;
+        let mut offset = 0;
 
         loop {
-            let column = self.column - s.len();
+            if self.done {
+                return None;
+            }
             match self.bytes.next() {
-                Some(Err(_)) => {
-                    return None;
+                Some(Err(err)) => {
+                    self.done = true;
+                    let chunk = Chunk::new(String::new(), self.line, self.column, self.offset);
+                    let message = format!("could not read: {err}");
+                    let error = Error::new(message, chunk, self.file.clone()).with_code("E0001");
+                    return Some(Err(error));
                 }
                 None => {
+                    self.done = true;
                     if !s.is_empty() {
-                        match String::from_utf8(s) {
-                            Ok(string) => {
-                                return Some(Ok(Chunk::new(string, self.line, column)));
-                            }
-                            Err(_) => {
-                                return None;
-                            }
-                        }
+                        return Some(self.chunk(s, column, offset));
                     }
                     return None;
                 }
                 Some(Ok(byte)) => {
-                    if is_whitespace(byte) && !s.is_empty() {
-                        match String::from_utf8(s) {
-                            Ok(string) => {
-                                self.column += 1;
-                                let value = Some(Ok(Chunk::new(string, self.line, column)));
-                                if byte == b'\n' {
-                                    self.line += 1;
-                                    self.column = 0;
-                                }
-                                return value;
-                            }
-                            Err(_) => {
-                                return None;
-                            }
-                        }
-                    } else if is_whitespace(byte) {
+                    self.offset += 1;
+                    if is_whitespace(byte) {
+                        let chunk = if s.is_empty() {
+                            None
+                        } else {
+                            Some(self.chunk(s.clone(), column, offset))
+                        };
                         if byte == b'\n' {
                             self.line += 1;
                             self.column = 0;
                         } else {
                             self.column += 1;
                         }
+                        if chunk.is_some() {
+                            return chunk;
+                        }
                     } else {
+                        if s.is_empty() {
+                            column = self.column;
+                            offset = self.offset - 1;
+                        }
                         s.push(byte);
-                        self.column += 1;
+                        if starts_character(byte) {
+                            self.column += 1;
+                        }
                     }
                 }
             }
@@ -106,23 +172,23 @@ mod tests {
     #[test]
     fn it_works() {
         let mut buffer = Cursor::new("cat\ndog\trat possum");
-        let mut breaker = Chunker::new(&mut buffer);
+        let mut breaker = Chunker::new(PathBuf::new(), &mut buffer);
 
         assert_eq!(
             breaker.next(),
-            Some(Ok(Chunk::new(String::from("cat"), 0, 0)))
+            Some(Ok(Chunk::new(String::from("cat"), 0, 0, 0)))
         );
         assert_eq!(
             breaker.next(),
-            Some(Ok(Chunk::new(String::from("dog"), 1, 0)))
+            Some(Ok(Chunk::new(String::from("dog"), 1, 0, 4)))
         );
         assert_eq!(
             breaker.next(),
-            Some(Ok(Chunk::new(String::from("rat"), 1, 4)))
+            Some(Ok(Chunk::new(String::from("rat"), 1, 4, 8)))
         );
         assert_eq!(
             breaker.next(),
-            Some(Ok(Chunk::new(String::from("possum"), 1, 8)))
+            Some(Ok(Chunk::new(String::from("possum"), 1, 8, 12)))
         );
         assert_eq!(breaker.next(), None);
     }
@@ -130,24 +196,74 @@ mod tests {
     #[test]
     fn it_works2() {
         let mut buffer = Cursor::new("cat\n\ndog\trat possum");
-        let mut breaker = Chunker::new(&mut buffer);
+        let mut breaker = Chunker::new(PathBuf::new(), &mut buffer);
 
         assert_eq!(
             breaker.next(),
-            Some(Ok(Chunk::new(String::from("cat"), 0, 0)))
+            Some(Ok(Chunk::new(String::from("cat"), 0, 0, 0)))
         );
         assert_eq!(
             breaker.next(),
-            Some(Ok(Chunk::new(String::from("dog"), 2, 0)))
+            Some(Ok(Chunk::new(String::from("dog"), 2, 0, 5)))
         );
         assert_eq!(
             breaker.next(),
-            Some(Ok(Chunk::new(String::from("rat"), 2, 4)))
+            Some(Ok(Chunk::new(String::from("rat"), 2, 4, 9)))
         );
         assert_eq!(
             breaker.next(),
-            Some(Ok(Chunk::new(String::from("possum"), 2, 8)))
+            Some(Ok(Chunk::new(String::from("possum"), 2, 8, 13)))
         );
         assert_eq!(breaker.next(), None);
     }
+
+    fn chunks(source: &[u8]) -> Vec<Result<(String, usize, usize, usize), String>> {
+        let mut buffer = Cursor::new(source);
+        let chunker = Chunker::new(PathBuf::from("foo.tal"), &mut buffer);
+        chunker
+            .map(|chunk| match chunk {
+                Ok(chunk) => Ok((chunk.value, chunk.line, chunk.column, chunk.offset)),
+                Err(err) => Err(format!("{} {:?}", err.message(), err.chunk().value)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_handles_crlf_and_form_feeds() {
+        assert_eq!(
+            chunks(b"LIT 68\r\nDEO\r\n\x0c\r\nBRK"),
+            vec![
+                Ok(("LIT".to_string(), 0, 0, 0)),
+                Ok(("68".to_string(), 0, 4, 4)),
+                Ok(("DEO".to_string(), 1, 0, 8)),
+                Ok(("BRK".to_string(), 3, 0, 16)),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_counts_columns_in_characters() {
+        assert_eq!(
+            chunks("( λ→ ) @λ \"é".as_bytes()),
+            vec![
+                Ok(("(".to_string(), 0, 0, 0)),
+                Ok(("λ→".to_string(), 0, 2, 2)),
+                Ok((")".to_string(), 0, 5, 8)),
+                Ok(("@λ".to_string(), 0, 7, 10)),
+                Ok(("\"é".to_string(), 0, 10, 14)),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_fails() {
+        assert_eq!(
+            chunks(b"LIT \xff\xfe DEO"),
+            vec![
+                Ok(("LIT".to_string(), 0, 0, 0)),
+                Err("invalid UTF-8 \"\u{fffd}\u{fffd}\"".to_string()),
+                Ok(("DEO".to_string(), 0, 7, 7)),
+            ]
+        );
+    }
 }
@@ -0,0 +1,534 @@
+use crate::chunker::Chunk;
+use crate::opcode::Opcode;
+use crate::symbols::Symbols;
+use crate::token::TokenType;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Write;
+
+// ROMs are loaded into memory at this address
+const ROM_START: usize = 0x100;
+// Shorter runs are more likely to be code that happens to look like data
+const MIN_ZERO_RUN: usize = 4;
+const MIN_ASCII_RUN: usize = 4;
+const BYTES_PER_LINE: usize = 16;
+const CHARACTERS_PER_LINE: usize = 32;
+// Code lines wrap before they get longer than this, not counting indentation
+const LINE_WIDTH: usize = 80;
+
+// A piece of the ROM and how it's written out
+#[derive(Debug, PartialEq)]
+enum Item {
+    Opcode(Opcode),
+    // LIT and LIT2 with their operand, written as #12 or #1234
+    Literal(Vec<u8>),
+    // LITr and LIT2r, which have no shorthand, with their operand
+    ReturnLiteral(Opcode, Vec<u8>),
+    // JCI, JMI or JSI with its operand and the address that works out to
+    Immediate(Opcode, Vec<u8>, u16),
+    Ascii(String),
+    Bytes(Vec<u8>),
+}
+
+impl Item {
+    fn length(&self) -> usize {
+        match self {
+            Item::Opcode(_) => 1,
+            Item::Literal(operand) | Item::ReturnLiteral(_, operand) => 1 + operand.len(),
+            Item::Immediate(_, _, _) => 3,
+            Item::Ascii(text) => text.len(),
+            Item::Bytes(bytes) => bytes.len(),
+        }
+    }
+
+    // Code that doesn't carry on to the next byte, so the next item starts a new line
+    fn ends_flow(&self) -> bool {
+        matches!(
+            self,
+            Item::Opcode(Opcode::BRK)
+                | Item::Opcode(Opcode::JMP(_, false, _))
+                | Item::Immediate(Opcode::JMI, _, _)
+        )
+    }
+}
+
+fn label_name(address: u16) -> String {
+    format!("L{address:04x}")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn run_length(bytes: &[u8], matches: impl Fn(u8) -> bool) -> usize {
+    bytes.iter().take_while(|byte| matches(**byte)).count()
+}
+
+// Text has to be a single token, so it can't contain whitespace. Most of it should be letters
+// and digits, otherwise it's probably code made of opcodes that happen to be printable
+fn ascii_run(bytes: &[u8]) -> usize {
+    let length = run_length(bytes, |byte| byte.is_ascii_graphic());
+    let alphanumeric = bytes[..length]
+        .iter()
+        .filter(|byte| byte.is_ascii_alphanumeric())
+        .count();
+    if length >= MIN_ASCII_RUN && alphanumeric * 4 >= length * 3 {
+        length
+    } else {
+        0
+    }
+}
+
+// Splits the ROM into items, each paired with the address it starts at. Nothing crosses a
+// boundary, so that a label can be placed there
+fn decode(bytes: &[u8], boundaries: &BTreeSet<usize>) -> Vec<(usize, Item)> {
+    let mut items = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let address = ROM_START + i;
+        let end = match boundaries.range(address + 1..).next() {
+            Some(boundary) => (boundary - ROM_START).min(bytes.len()),
+            None => bytes.len(),
+        };
+        let rest = &bytes[i..end];
+
+        let zeros = run_length(rest, |byte| byte == 0);
+        let ascii = ascii_run(rest);
+        let item = if zeros >= MIN_ZERO_RUN {
+            Item::Bytes(rest[..zeros.min(BYTES_PER_LINE)].to_vec())
+        } else if ascii > 0 {
+            let text = &rest[..ascii.min(CHARACTERS_PER_LINE)];
+            Item::Ascii(String::from_utf8_lossy(text).to_string())
+        } else {
+            let opcode = Opcode::from_byte(rest[0]);
+            let operand = match opcode {
+                Opcode::LIT(two, _) => Some(if two { 2 } else { 1 }),
+                Opcode::JCI | Opcode::JMI | Opcode::JSI => Some(2),
+                _ => None,
+            };
+            match operand {
+                // Cut off by the end of the ROM or by a label
+                Some(length) if rest.len() <= length => Item::Bytes(vec![rest[0]]),
+                Some(length) => {
+                    let operand = rest[1..=length].to_vec();
+                    match opcode {
+                        Opcode::LIT(_, false) => Item::Literal(operand),
+                        Opcode::LIT(_, true) => Item::ReturnLiteral(opcode, operand),
+                        _ => {
+                            let offset = i16::from_be_bytes([operand[0], operand[1]]);
+                            let target = (address as i32 + 3 + offset as i32) as u16;
+                            Item::Immediate(opcode, operand, target)
+                        }
+                    }
+                }
+                None => Item::Opcode(opcode),
+            }
+        };
+        i += item.length();
+        items.push((address, item));
+    }
+    items
+}
+
+// Every label in the output, by full name like parent/child
+struct Labels {
+    // In the order they're written out, which is by address
+    definitions: Vec<(u16, String)>,
+    // What references to an address are written as, the last label defined there
+    names: HashMap<u16, String>,
+}
+
+// The part of name after scope/, if it can be written as &child
+fn child_name<'a>(name: &'a str, scope: &Option<String>) -> Option<&'a str> {
+    name.strip_prefix(scope.as_deref()?)?.strip_prefix('/')
+}
+
+// Symbols from the file, plus a label at every jump target without one. Made-up labels inside a
+// routine become its children, so they don't interrupt its &child labels
+fn find_labels(items: &[(usize, Item)], symbols: &Symbols) -> Labels {
+    // Lambdas are named λ00 and so on, which would split their routine in two
+    let mut definitions: Vec<(u16, String)> = symbols
+        .sorted()
+        .into_iter()
+        .filter(|symbol| !symbol.name.starts_with('λ'))
+        .map(|symbol| (symbol.address, symbol.name.clone()))
+        .collect();
+
+    let named: HashSet<u16> = definitions.iter().map(|(address, _)| *address).collect();
+    let starts: HashSet<u16> = items.iter().map(|(address, _)| *address as u16).collect();
+    let targets: BTreeSet<u16> = items
+        .iter()
+        .filter_map(|(_, item)| match item {
+            Item::Immediate(_, _, target) if starts.contains(target) => Some(*target),
+            _ => None,
+        })
+        .filter(|target| !named.contains(target))
+        .collect();
+
+    let mut scope: Option<String> = None;
+    let mut symbols = definitions.iter().peekable();
+    let mut generated = vec![];
+    for target in targets {
+        while let Some((_, name)) = symbols.next_if(|(address, _)| *address <= target) {
+            if child_name(name, &scope).is_none() {
+                scope = Some(name.clone());
+            }
+        }
+        let name = match &scope {
+            Some(parent) => format!("{parent}/{}", label_name(target)),
+            None => label_name(target),
+        };
+        generated.push((target, name));
+    }
+    definitions.extend(generated);
+    definitions.sort_by_key(|(address, _)| *address);
+
+    let names = definitions.iter().cloned().collect();
+    Labels { definitions, names }
+}
+
+// A reference to name starting with rune, such as ;name or ,&child
+fn reference(rune: &str, name: &str, scope: &Option<String>) -> String {
+    match child_name(name, scope) {
+        Some(child) => format!("{rune}&{child}"),
+        None => format!("{rune}{name}"),
+    }
+}
+
+// The definition of name, which starts a new scope unless it's a child of the current one
+fn definition(name: &str, scope: &mut Option<String>) -> String {
+    match child_name(name, scope) {
+        Some(child) => format!("&{child}"),
+        None => {
+            *scope = Some(name.to_string());
+            format!("@{name}")
+        }
+    }
+}
+
+// A one-byte literal is an address when the opcode after it takes one: a zero-page or device
+// address, or a relative one
+fn render_byte_literal(
+    address: usize,
+    byte: u8,
+    next: Option<&Item>,
+    labels: &Labels,
+    scope: &Option<String>,
+) -> Option<String> {
+    let (rune, target) = match next? {
+        Item::Opcode(
+            Opcode::LDZ(_, _, _)
+            | Opcode::STZ(_, _, _)
+            | Opcode::DEI(_, _, _)
+            | Opcode::DEO(_, _, _),
+        ) => (".", byte as u16),
+        Item::Opcode(
+            Opcode::JMP(false, _, _)
+            | Opcode::JCN(false, _, _)
+            | Opcode::JSR(false, _, _)
+            | Opcode::LDR(_, _, _)
+            | Opcode::STR(_, _, _),
+        ) => (",", (address as i32 + 3 + byte as i8 as i32) as u16),
+        _ => return None,
+    };
+    let name = labels.names.get(&target)?;
+    Some(reference(rune, name, scope))
+}
+
+fn render(
+    address: usize,
+    item: &Item,
+    next: Option<&Item>,
+    labels: &Labels,
+    scope: &Option<String>,
+) -> String {
+    match item {
+        Item::Opcode(opcode) => opcode.to_string(),
+        Item::Literal(operand) => {
+            let name = match operand[..] {
+                [byte] => render_byte_literal(address, byte, next, labels, scope),
+                // Outside the zero page, where shorts are more likely to be numbers
+                [high, low] if high > 0 => labels
+                    .names
+                    .get(&u16::from_be_bytes([high, low]))
+                    .map(|name| reference(";", name, scope)),
+                _ => None,
+            };
+            name.unwrap_or_else(|| format!("#{}", hex(operand)))
+        }
+        Item::ReturnLiteral(opcode, operand) => format!("{} {}", opcode, hex(operand)),
+        Item::Immediate(opcode, operand, target) => {
+            let name = labels.names.get(target).and_then(|name| match opcode {
+                Opcode::JCI => Some(reference("?", name, scope)),
+                Opcode::JMI => Some(reference("!", name, scope)),
+                // A call is just the name, so it mustn't read as anything else
+                _ => match TokenType::from_chunk(&Chunk::new(name.clone(), 0, 0, 0)) {
+                    Ok(TokenType::Instant(_)) => Some(name.clone()),
+                    _ => None,
+                },
+            });
+            // Somewhere without a label, such as the middle of an item or outside the ROM
+            name.unwrap_or_else(|| format!("{} {} ( {:04x} )", opcode, hex(operand), target))
+        }
+        Item::Ascii(text) => format!("\"{text}"),
+        Item::Bytes(bytes) => {
+            let bytes: Vec<String> = bytes.iter().map(|byte| hex(&[*byte])).collect();
+            bytes.join(" ")
+        }
+    }
+}
+
+// Writes uxntal that assembles back to exactly bytes. Labels from symbols are placed at their
+// addresses, padding out to the ones outside the ROM, and jumps get labels where they land on
+// the start of something. Runs of zeros and text are written as data
+pub fn disassemble(bytes: &[u8], symbols: &Symbols, writer: &mut dyn Write) -> std::io::Result<()> {
+    let end = ROM_START + bytes.len();
+    let boundaries = symbols
+        .iter()
+        .map(|symbol| symbol.address as usize)
+        .filter(|address| (ROM_START..end).contains(address))
+        .collect();
+    let items = decode(bytes, &boundaries);
+    let labels = find_labels(&items, symbols);
+
+    let mut scope = None;
+    let mut definitions = labels.definitions.iter().peekable();
+    let mut zero_page = vec![];
+    while let Some(label) = definitions.next_if(|(address, _)| (*address as usize) < ROM_START) {
+        zero_page.push(label);
+    }
+    write_outside(writer, &zero_page, &mut scope)?;
+    // Still the last device or variable, but references to it from the code would be confusing
+    // as &child, so they're written in full until the code sets its own
+    scope = None;
+
+    writeln!(writer, "|0100")?;
+    let mut line: Vec<String> = vec![];
+    for (i, (address, item)) in items.iter().enumerate() {
+        let mut labelled = false;
+        while let Some((_, name)) = definitions.next_if(|(label, _)| *label as usize <= *address) {
+            write_line(writer, &mut line)?;
+            writeln!(writer, "{}", definition(name, &mut scope))?;
+            labelled = true;
+        }
+        let data = matches!(item, Item::Ascii(_) | Item::Bytes(_));
+        let next = items.get(i + 1).map(|(_, item)| item);
+        let token = render(*address, item, next, &labels, &scope);
+        let width: usize = line.iter().map(|token| token.len() + 1).sum();
+        if !labelled && (data || width + token.len() > LINE_WIDTH) {
+            write_line(writer, &mut line)?;
+        }
+        line.push(token);
+        if data || item.ends_flow() {
+            write_line(writer, &mut line)?;
+        }
+    }
+    write_line(writer, &mut line)?;
+
+    // Labels past the end of the ROM, such as variables in memory that starts zeroed
+    let rest: Vec<&(u16, String)> = definitions.collect();
+    write_outside(writer, &rest, &mut scope)
+}
+
+fn padding(address: u16) -> String {
+    if (address as usize) < ROM_START {
+        format!("|{address:02x}")
+    } else {
+        format!("|{address:04x}")
+    }
+}
+
+// Labels outside the ROM, a line for each parent with its children padded apart, the way a
+// device or struct is written: |00 @System &vector $2 &wst
+fn write_outside(
+    writer: &mut dyn Write,
+    definitions: &[&(u16, String)],
+    scope: &mut Option<String>,
+) -> std::io::Result<()> {
+    let mut groups: Vec<(&str, Vec<&(u16, String)>)> = vec![];
+    for label in definitions {
+        let parent = label.1.split('/').next().unwrap_or_default();
+        match groups.iter_mut().find(|(name, _)| *name == parent) {
+            Some((_, group)) => group.push(label),
+            None => groups.push((parent, vec![label])),
+        }
+    }
+
+    for (_, group) in groups {
+        let mut tokens = vec![];
+        let mut position = None;
+        for (address, name) in group {
+            match position {
+                Some(position) if *address == position => {}
+                Some(position) if *address > position => {
+                    tokens.push(format!("${:x}", address - position))
+                }
+                _ => tokens.push(padding(*address)),
+            }
+            position = Some(*address);
+            tokens.push(definition(name, scope));
+        }
+        writeln!(writer, "{}", tokens.join(" "))?;
+    }
+    Ok(())
+}
+
+fn write_line(writer: &mut dyn Write, line: &mut Vec<String>) -> std::io::Result<()> {
+    if !line.is_empty() {
+        writeln!(writer, "    {}", line.join(" "))?;
+        line.clear();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+    use crate::Options;
+
+    fn disassemble_to_string(bytes: &[u8]) -> String {
+        let mut output = vec![];
+        disassemble(bytes, &Symbols::new(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    // Disassembles bytes, checks the result, and checks it assembles back to bytes
+    fn assert_roundtrip(bytes: &[u8], expected: &str) {
+        let source = disassemble_to_string(bytes);
+        assert_eq!(source, expected);
+        let options = Options {
+            no_trim: true,
+            ..Options::default()
+        };
+        let assembly = assemble(&source, &options).unwrap();
+        assert_eq!(assembly.rom.get_bytes(), bytes);
+    }
+
+    #[test]
+    fn it_works() {
+        assert_roundtrip(
+            &[0x80, 0x68, 0x80, 0x18, 0x17, 0x00],
+            "|0100\n    #68 #18 DEO BRK\n",
+        );
+    }
+
+    #[test]
+    fn literals_work() {
+        assert_roundtrip(
+            &[0xa0, 0x12, 0x34, 0xc0, 0x56, 0xe0, 0x78, 0x9a, 0x26],
+            "|0100\n    #1234 LITr 56 LIT2r 789a DUP2\n",
+        );
+    }
+
+    #[test]
+    fn modes_work() {
+        assert_roundtrip(
+            &[0x18, 0x38, 0x58, 0x98, 0xf8],
+            "|0100\n    ADD ADD2 ADDr ADDk ADD2kr\n",
+        );
+    }
+
+    #[test]
+    fn immediate_jumps_get_labels() {
+        // JSI to the BRK, then JCI back to the start, then JMI to itself
+        assert_roundtrip(
+            &[0x60, 0x00, 0x06, 0x20, 0xff, 0xfa, 0x40, 0xff, 0xfd, 0x00],
+            "|0100\n@L0100\n    L0109 ?L0100\n@L0106\n    !L0106\n@L0109\n    BRK\n",
+        );
+    }
+
+    #[test]
+    fn immediate_jumps_elsewhere_are_written_raw() {
+        // Into the middle of the LIT, and past the end of the ROM
+        assert_roundtrip(
+            &[0x80, 0x12, 0x20, 0xff, 0xfc, 0x60, 0x10, 0x00],
+            "|0100\n    #12 JCI fffc ( 0101 ) JSI 1000 ( 1108 )\n",
+        );
+    }
+
+    #[test]
+    fn data_works() {
+        assert_roundtrip(
+            &[
+                0x00, 0x00, 0x00, 0x00, 0x00, b'H', b'e', b'l', b'l', b'o', 0x17,
+            ],
+            "|0100\n    00 00 00 00 00\n    \"Hello\n    DEO\n",
+        );
+    }
+
+    #[test]
+    fn truncated_operands_are_written_as_bytes() {
+        assert_roundtrip(&[0x26, 0xa0, 0x12], "|0100\n    DUP2\n    a0\n    LDR\n");
+        assert_roundtrip(&[0x60, 0x12], "|0100\n    60\n    LDR\n");
+    }
+
+    // Assembles source, then checks disassembling it with its symbols gives expected and that
+    // expected assembles back to the same bytes
+    fn assert_symbols_roundtrip(source: &str, expected: &str) {
+        let options = Options {
+            no_trim: true,
+            ..Options::default()
+        };
+        let assembly = assemble(source, &options).unwrap();
+        let bytes = assembly.rom.get_bytes();
+
+        let mut output = vec![];
+        disassemble(bytes, &assembly.symbols, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(assemble(&output, &options).unwrap().rom.get_bytes(), bytes);
+    }
+
+    #[test]
+    fn symbols_are_used() {
+        let source = "
+|00 @System &vector $2 &wst
+|10 @Console &vector $2 &read $5 &write
+|0100
+@on-reset
+    ;on-console .Console/vector DEO2
+    #03 &loop #01 SUB DUP ?&loop
+    POP print BRK
+@print
+    .Console/read DEI .Console/write DEO !on-reset
+@on-console
+    LIT &count $1 INC ,&count STR BRK
+|0200 @buffer $10 @end
+";
+        let expected = "\
+|00 @System &vector $2 &wst
+|10 @Console &vector $2 &read $5 &write
+|0100
+@on-reset
+    ;on-console .Console/vector DEO2 #03
+&loop
+    #01 SUB DUP ?&loop POP print BRK
+@print
+    .Console/read DEI .Console/write DEO !on-reset
+@on-console
+    80
+&count
+    BRK
+    INC ,&count STR BRK
+|0200 @buffer
+|0210 @end
+";
+        assert_symbols_roundtrip(source, expected);
+    }
+
+    #[test]
+    fn jumps_without_symbols_get_child_labels() {
+        // The lambda's label is left out, it would end the routine's scope
+        let source = "|0100 @main #01 ?{ #02 } #03 BRK";
+        let expected = "\
+|0100
+@main
+    #01 ?&L0107 #02
+&L0107
+    #03 BRK
+";
+        assert_symbols_roundtrip(source, expected);
+    }
+}
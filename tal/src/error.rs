@@ -1,12 +1,79 @@
 use crate::chunker::Chunk;
-use std::io::BufRead;
+use crate::chunker::Include;
+use crate::json::quote;
+use std::path::Path;
 use std::path::PathBuf;
+use std::rc::Rc;
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// A secondary chunk that helps explain an error, such as where a label was first defined
+#[derive(Debug, PartialEq, Clone)]
+pub struct Label {
+    pub message: String,
+    pub chunk: Chunk,
+    pub file: PathBuf,
+}
+
+// Everything beyond the message and where it happened, boxed so Results carrying an Error stay
+// small
+#[derive(Debug, PartialEq)]
+struct Notes {
+    severity: Severity,
+    code: Option<&'static str>,
+    labels: Vec<Label>,
+    help: Vec<String>,
+}
 
 #[derive(Debug, PartialEq)]
 pub struct Error {
     message: String,
     chunk: Chunk,
     file: PathBuf,
+    notes: Box<Notes>,
+}
+
+// The line chunk is on with tabs expanded, the indent that lines up with the chunk, and markers
+// to go underneath it
+fn snippet(source: &[u8], chunk: &Chunk, marker: char) -> (String, String, String) {
+    // Read the line lossily, the error may be that it isn't valid UTF-8
+    let line = match source.split(|byte| *byte == b'\n').nth(chunk.line) {
+        Some(line) => String::from_utf8_lossy(line).to_string(),
+        None => String::new(),
+    };
+    let line = line.strip_suffix('\r').unwrap_or(&line);
+
+    let mut indent = String::new();
+    for chr in line.chars().take(chunk.column) {
+        let width = if chr == '\t' { 8 } else { 1 };
+        indent.push_str(&" ".repeat(width));
+    }
+    let markers = marker
+        .to_string()
+        .repeat(chunk.value.chars().count().max(1));
+
+    (line.replace('\t', &" ".repeat(8)), indent, markers)
+}
+
+fn position_json(chunk: &Chunk, file: &Path) -> String {
+    format!(
+        "\"file\": {}, \"line\": {}, \"column\": {}, \"offset\": {}, \"length\": {}",
+        quote(&file.display().to_string()),
+        chunk.line + 1,
+        chunk.column + 1,
+        chunk.offset,
+        chunk.value.chars().count()
+    )
 }
 
 impl Error {
@@ -15,34 +82,235 @@ impl Error {
             message,
             chunk,
             file,
+            notes: Box::new(Notes {
+                severity: Severity::Error,
+                code: None,
+                labels: vec![],
+                help: vec![],
+            }),
         }
     }
 
-    pub fn to_string_with_context(&self, reader: &mut dyn BufRead) -> String {
-        let line = reader.lines().nth(self.chunk.line);
-        let line = line.unwrap();
-        let line = line.unwrap();
+    pub fn warning(message: String, chunk: Chunk, file: PathBuf) -> Error {
+        let mut warning = Error::new(message, chunk, file);
+        warning.notes.severity = Severity::Warning;
+        warning
+    }
+
+    // Identifies the kind of error, so it can be looked up in the README
+    pub fn with_code(mut self, code: &'static str) -> Error {
+        self.notes.code = Some(code);
+        self
+    }
+
+    pub fn with_label(mut self, message: String, chunk: Chunk, file: PathBuf) -> Error {
+        self.notes.labels.push(Label {
+            message,
+            chunk,
+            file,
+        });
+        self
+    }
+
+    // A suggestion for fixing the error
+    pub fn with_help(mut self, help: String) -> Error {
+        self.notes.help.push(help);
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.notes.severity
+    }
+
+    pub fn code(&self) -> Option<&'static str> {
+        self.notes.code
+    }
+
+    pub fn labels(&self) -> &[Label] {
+        &self.notes.labels
+    }
 
-        let tab_count = line.matches('\t').count();
+    pub fn help(&self) -> &[String] {
+        &self.notes.help
+    }
 
-        // Only add 7 for each tab, because each character in the string gets 1 added later on
-        let mut arrows = " ".to_string().repeat(7).repeat(tab_count);
-        for _ in 0..self.chunk.column {
-            arrows.push(' ');
+    // The files that included the one the error is in, innermost first, with the chunks that
+    // included them
+    pub fn included_from(&self) -> Vec<(&Path, &Chunk)> {
+        match &self.chunk.include {
+            Some(include) => include
+                .included_from
+                .iter()
+                .rev()
+                .map(|(file, chunk)| (file.as_path(), chunk))
+                .collect(),
+            None => vec![],
         }
-        for _ in 0..self.chunk.value.len() {
-            arrows.push('^');
+    }
+
+    // The file being assembled, which chunks that weren't included came from
+    fn main_file(&self) -> &Path {
+        match &self.chunk.include {
+            Some(include) => &include.included_from[0].0,
+            None => &self.file,
         }
+    }
 
-        let line = line.replace('\t', &" ".repeat(8));
+    // The macros that were expanded to produce the error's chunk, innermost first, with the
+    // files and chunks they were invoked at
+    pub fn expansions(&self) -> Vec<(&str, &Path, &Chunk)> {
+        let main = self.main_file();
+        let mut frames = vec![];
+        let mut expansion = &self.chunk.expansion;
+        while let Some(frame) = expansion {
+            frames.push((frame.name.as_str(), frame.chunk.file(main), &frame.chunk));
+            expansion = &frame.chunk.expansion;
+        }
+        frames
+    }
 
-        format!(
-            "{}:{}: Error: {}\n\n{}\n{}",
+    // Records that the error's chunk was read from an included file, unless it already knows
+    pub(crate) fn with_include(mut self, include: &Option<Rc<Include>>) -> Error {
+        if self.chunk.include.is_none() {
+            self.chunk.include = include.clone();
+        }
+        self
+    }
+
+    // The error with the line it's on, the files that included that file, then any labels and
+    // help. sources returns the contents of a file, so lines can be quoted from whichever file
+    // they're in. colour adds ANSI escape codes for terminals
+    pub fn render(&self, sources: &dyn Fn(&Path) -> Vec<u8>, colour: bool) -> String {
+        let paint = |colour_code: &str, text: &str| {
+            if colour {
+                format!("{colour_code}{text}{RESET}")
+            } else {
+                text.to_string()
+            }
+        };
+
+        let (severity, severity_colour) = match self.notes.severity {
+            Severity::Error => ("Error", RED),
+            Severity::Warning => ("Warning", YELLOW),
+        };
+        let severity = match self.notes.code {
+            Some(code) => format!("{severity}[{code}]"),
+            None => severity.to_string(),
+        };
+
+        let (line, indent, markers) = snippet(&sources(&self.file), &self.chunk, '^');
+        let mut rendered = format!(
+            "{}:{}: {}: {}\n\n{}\n{}{}",
             self.file.display(),
             self.chunk.line + 1,
-            self.message,
+            paint(severity_colour, &severity),
+            paint(BOLD, &self.message),
             line,
-            arrows
+            indent,
+            paint(severity_colour, &markers)
+        );
+
+        if !self.expansions().is_empty() || !self.included_from().is_empty() {
+            rendered.push('\n');
+        }
+        for (name, file, chunk) in self.expansions() {
+            rendered.push_str(&format!(
+                "\nin expansion of {} at {}:{}",
+                name,
+                file.display(),
+                chunk.line + 1
+            ));
+        }
+        for (file, chunk) in self.included_from() {
+            rendered.push_str(&format!(
+                "\nincluded from {}:{}",
+                file.display(),
+                chunk.line + 1
+            ));
+        }
+
+        for label in &self.notes.labels {
+            let (line, indent, markers) = snippet(&sources(&label.file), &label.chunk, '-');
+            rendered.push_str(&format!(
+                "\n\n{}:{}: {}\n\n{}\n{}{}",
+                label.file.display(),
+                label.chunk.line + 1,
+                label.message,
+                line,
+                indent,
+                paint(BLUE, &markers)
+            ));
+        }
+
+        for help in &self.notes.help {
+            rendered.push_str(&format!("\n\n{}: {}", paint(BLUE, "help"), help));
+        }
+
+        rendered
+    }
+
+    // The error as a single line JSON object, for editors and CI
+    pub fn to_json(&self) -> String {
+        let severity = match self.notes.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let code = match self.notes.code {
+            Some(code) => quote(code),
+            None => "null".to_string(),
+        };
+        let labels: Vec<String> = self
+            .notes
+            .labels
+            .iter()
+            .map(|label| {
+                format!(
+                    "{{\"message\": {}, {}}}",
+                    quote(&label.message),
+                    position_json(&label.chunk, &label.file)
+                )
+            })
+            .collect();
+        let help: Vec<String> = self.notes.help.iter().map(|help| quote(help)).collect();
+        let expansions: Vec<String> = self
+            .expansions()
+            .iter()
+            .map(|(name, file, chunk)| {
+                format!(
+                    "{{\"macro\": {}, {}}}",
+                    quote(name),
+                    position_json(chunk, file)
+                )
+            })
+            .collect();
+        let included_from: Vec<String> = self
+            .included_from()
+            .iter()
+            .map(|(file, chunk)| format!("{{{}}}", position_json(chunk, file)))
+            .collect();
+
+        format!(
+            "{{\"severity\": \"{}\", \"code\": {}, \"message\": {}, {}, \"expansion\": [{}], \"included_from\": [{}], \"labels\": [{}], \"help\": [{}]}}",
+            severity,
+            code,
+            quote(&self.message),
+            position_json(&self.chunk, &self.file),
+            expansions.join(", "),
+            included_from.join(", "),
+            labels.join(", "),
+            help.join(", ")
         )
     }
 }
@@ -50,49 +318,144 @@ impl Error {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Cursor;
 
     #[test]
     fn it_works() {
-        let mut reader = Cursor::new("FOO\nBAR\nBAZ\nBAT cat");
+        let source = "FOO\nBAR\nBAZ\nBAT cat";
         let err = Error::new(
             "Unknown token \"cat\"".to_string(),
-            Chunk::new("cat".to_string(), 3, 4),
+            Chunk::new("cat".to_string(), 3, 4, 16),
             PathBuf::from("foo.tal"),
         );
-        let error_with_context = err.to_string_with_context(&mut reader);
+        let error_with_context = err.render(&|_| source.as_bytes().to_vec(), false);
         let expected = "foo.tal:4: Error: Unknown token \"cat\"\n\nBAT cat\n    ^^^";
         assert_eq!(error_with_context, expected);
     }
 
     #[test]
-    fn it_handles_tabs() {
-        let err = Error {
-            message: "could not parse AddressLiteralAbsoluteByte".to_string(),
-            chunk: Chunk::new(".octave".to_string(), 108, 32),
-            file: PathBuf::from("foo.tal"),
-        };
+    fn it_renders_warnings() {
+        let source = "@foo BRK";
+        let err = Error::warning(
+            "label \"foo\" is never used".to_string(),
+            Chunk::new("@foo".to_string(), 0, 0, 0),
+            PathBuf::from("foo.tal"),
+        );
+        let error_with_context = err.render(&|_| source.as_bytes().to_vec(), false);
+        let expected = "foo.tal:1: Warning: label \"foo\" is never used\n\n@foo BRK\n^^^^";
+        assert_eq!(error_with_context, expected);
+    }
 
-        let mut reader = Cursor::new(
-            "\n".repeat(108)
-                + "\t[ LIT \"a ] NEQk NIP ?&no-c #30 .octave LDZ #0c MUL ADD play &no-c\n",
+    #[test]
+    fn it_handles_tabs() {
+        let err = Error::new(
+            "could not parse AddressLiteralAbsoluteByte".to_string(),
+            Chunk::new(".octave".to_string(), 108, 32, 140),
+            PathBuf::from("foo.tal"),
         );
-        let error_with_context = err.to_string_with_context(&mut reader);
+
+        let source = "\n".repeat(108)
+            + "\t[ LIT \"a ] NEQk NIP ?&no-c #30 .octave LDZ #0c MUL ADD play &no-c\n";
+        let error_with_context = err.render(&|_| source.as_bytes().to_vec(), false);
         let expected = "foo.tal:109: Error: could not parse AddressLiteralAbsoluteByte\n\n        [ LIT \"a ] NEQk NIP ?&no-c #30 .octave LDZ #0c MUL ADD play &no-c\n                                       ^^^^^^^";
         assert_eq!(error_with_context, expected);
     }
 
     #[test]
     fn it_handles_two_tabs() {
-        let err = Error {
-            message: "could not parse AddressLiteralAbsoluteByte".to_string(),
-            chunk: Chunk::new(".center/x".to_string(), 31, 7),
-            file: PathBuf::from("foo.tal"),
-        };
+        let err = Error::new(
+            "could not parse AddressLiteralAbsoluteByte".to_string(),
+            Chunk::new(".center/x".to_string(), 31, 7, 38),
+            PathBuf::from("foo.tal"),
+        );
 
-        let mut reader = Cursor::new("\n".repeat(31) + "\t\tDUP2 .center/x STZ2");
-        let error_with_context = err.to_string_with_context(&mut reader);
+        let source = "\n".repeat(31) + "\t\tDUP2 .center/x STZ2";
+        let error_with_context = err.render(&|_| source.as_bytes().to_vec(), false);
         let expected = "foo.tal:32: Error: could not parse AddressLiteralAbsoluteByte\n\n                DUP2 .center/x STZ2\n                     ^^^^^^^^^";
         assert_eq!(error_with_context, expected);
     }
+
+    #[test]
+    fn it_handles_crlf_and_multi_byte_characters() {
+        let source = b"( \xce\xbb ) \xff\r\nBRK";
+        let err = Error::new(
+            "invalid UTF-8".to_string(),
+            Chunk::new("\u{fffd}".to_string(), 0, 6, 6),
+            PathBuf::from("foo.tal"),
+        );
+        let error_with_context = err.render(&|_| source.to_vec(), false);
+        let expected = "foo.tal:1: Error: invalid UTF-8\n\n( λ ) \u{fffd}\n      ^";
+        assert_eq!(error_with_context, expected);
+    }
+
+    #[test]
+    fn it_renders_codes_labels_and_help() {
+        let source = "@foo\n;foo\n  @foo";
+        let err = Error::new(
+            "label \"foo\" is already defined".to_string(),
+            Chunk::new("@foo".to_string(), 2, 2, 12),
+            PathBuf::from("foo.tal"),
+        )
+        .with_code("E0011")
+        .with_label(
+            "first defined here".to_string(),
+            Chunk::new("@foo".to_string(), 0, 0, 0),
+            PathBuf::from("foo.tal"),
+        )
+        .with_help("rename one of them".to_string());
+
+        let expected = "\
+foo.tal:3: Error[E0011]: label \"foo\" is already defined
+
+  @foo
+  ^^^^
+
+foo.tal:1: first defined here
+
+@foo
+----
+
+help: rename one of them";
+        assert_eq!(err.render(&|_| source.as_bytes().to_vec(), false), expected);
+
+        let expected = "\
+foo.tal:3: \x1b[1;31mError[E0011]\x1b[0m: \x1b[1mlabel \"foo\" is already defined\x1b[0m
+
+  @foo
+  \x1b[1;31m^^^^\x1b[0m
+
+foo.tal:1: first defined here
+
+@foo
+\x1b[1;34m----\x1b[0m
+
+\x1b[1;34mhelp\x1b[0m: rename one of them";
+        assert_eq!(err.render(&|_| source.as_bytes().to_vec(), true), expected);
+    }
+
+    #[test]
+    fn it_renders_json() {
+        let err = Error::new(
+            "label \"foo\" is already defined".to_string(),
+            Chunk::new("@foo".to_string(), 2, 2, 12),
+            PathBuf::from("foo.tal"),
+        )
+        .with_code("E0011")
+        .with_label(
+            "first defined here".to_string(),
+            Chunk::new("@foo".to_string(), 0, 0, 0),
+            PathBuf::from("foo.tal"),
+        )
+        .with_help("rename one of them".to_string());
+
+        let expected = r#"{"severity": "error", "code": "E0011", "message": "label \"foo\" is already defined", "file": "foo.tal", "line": 3, "column": 3, "offset": 12, "length": 4, "expansion": [], "included_from": [], "labels": [{"message": "first defined here", "file": "foo.tal", "line": 1, "column": 1, "offset": 0, "length": 4}], "help": ["rename one of them"]}"#;
+        assert_eq!(err.to_json(), expected);
+
+        let warning = Error::warning(
+            "label \"foo\" is never used".to_string(),
+            Chunk::new("@foo".to_string(), 0, 0, 0),
+            PathBuf::from("foo.tal"),
+        );
+        let expected = r#"{"severity": "warning", "code": null, "message": "label \"foo\" is never used", "file": "foo.tal", "line": 1, "column": 1, "offset": 0, "length": 4, "expansion": [], "included_from": [], "labels": [], "help": []}"#;
+        assert_eq!(warning.to_json(), expected);
+    }
 }
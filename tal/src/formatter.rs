@@ -0,0 +1,412 @@
+use crate::error::Error;
+use crate::lexer::lex;
+use crate::lexer::Lexeme;
+use crate::lexer::LexemeKind;
+use crate::token::parse_byte;
+use crate::token::parse_short;
+use std::path::PathBuf;
+
+const INDENT: &str = "\t";
+// How wide a tab is taken to be when deciding whether a line is too long
+const TAB_WIDTH: usize = 4;
+const MAX_WIDTH: usize = 80;
+
+// A line of source without its whitespace, empty for a blank line
+type Line = Vec<Lexeme>;
+
+// How deep a line is indented, and how deep the lines after it are
+#[derive(Clone, Copy)]
+struct Placement {
+    indent: usize,
+    after: usize,
+}
+
+fn split_lines(lexemes: Vec<Lexeme>) -> Vec<Line> {
+    let mut lines = vec![vec![]];
+    for lexeme in lexemes {
+        match lexeme.kind {
+            LexemeKind::Newline => lines.push(vec![]),
+            LexemeKind::Space => {}
+            _ => lines.last_mut().unwrap().push(lexeme),
+        }
+    }
+    lines
+}
+
+fn is_comment(lexeme: &Lexeme) -> bool {
+    lexeme.kind == LexemeKind::Comment
+}
+
+fn starts_with(lexeme: &Lexeme, runes: &[char]) -> bool {
+    lexeme.kind == LexemeKind::Word && lexeme.value().starts_with(runes)
+}
+
+// Padding and labels at the start of a line, along with any comments between them
+fn header_length(line: &[Lexeme]) -> usize {
+    line.iter()
+        .take_while(|lexeme| is_comment(lexeme) || starts_with(lexeme, &['|', '$', '@', '&']))
+        .count()
+}
+
+// Bytes, shorts, text and raw addresses, which can be split across lines however they fit
+fn is_data(lexeme: &Lexeme) -> bool {
+    let value = lexeme.value();
+    lexeme.kind == LexemeKind::Bracket
+        || (lexeme.kind == LexemeKind::Word
+            && (parse_byte(value).is_ok()
+                || parse_short(value).is_ok()
+                || value.starts_with(['"', ':', '=', '-', '_'])))
+}
+
+// @labels, padding and macros go against the margin and &labels one level in. Whatever follows
+// goes one level under the last label, one level in after padding without one, or against the
+// margin after a macro, and each unclosed { adds a level. Lines of only comments are indented
+// like the line below them
+fn place(lines: &[Line]) -> Vec<Placement> {
+    let mut level = 0;
+    let mut depth: usize = 0;
+    let mut placements: Vec<Option<Placement>> = vec![];
+    let mut fallbacks = vec![];
+    for line in lines {
+        fallbacks.push(level + depth);
+        let words: Vec<&Lexeme> = line.iter().filter(|lexeme| !is_comment(lexeme)).collect();
+        if words.is_empty() {
+            placements.push(None);
+            continue;
+        }
+
+        let closing = words.iter().take_while(|word| word.value() == "}").count();
+        depth = depth.saturating_sub(closing);
+        let margin = if starts_with(words[0], &['@', '|', '%']) {
+            0
+        } else if starts_with(words[0], &['&']) {
+            1
+        } else {
+            level
+        };
+        let indent = margin + depth;
+
+        let header = &line[..header_length(line)];
+        let last_label = header.iter().rev().find_map(|lexeme| {
+            if starts_with(lexeme, &['@']) {
+                Some(1)
+            } else if starts_with(lexeme, &['&']) {
+                Some(2)
+            } else {
+                None
+            }
+        });
+        level = match last_label {
+            Some(label_level) => label_level,
+            None if starts_with(words[0], &['|']) => 1,
+            None if starts_with(words[0], &['%']) => 0,
+            None => level,
+        };
+        for word in &words[closing..] {
+            match word.value() {
+                "{" | "?{" | "!{" => depth += 1,
+                "}" => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        placements.push(Some(Placement {
+            indent,
+            after: level + depth,
+        }));
+    }
+
+    // Comments belong to the line below them, unless there's a blank line in the way
+    let mut below: Option<Placement> = None;
+    let mut resolved = vec![];
+    for (i, placement) in placements.iter().enumerate().rev() {
+        let placement = match placement {
+            Some(placement) => *placement,
+            None if lines[i].is_empty() => Placement {
+                indent: 0,
+                after: fallbacks[i],
+            },
+            None => below.unwrap_or(Placement {
+                indent: fallbacks[i],
+                after: fallbacks[i],
+            }),
+        };
+        below = if lines[i].is_empty() {
+            None
+        } else {
+            Some(placement)
+        };
+        resolved.push(placement);
+    }
+    resolved.reverse();
+    resolved
+}
+
+fn width(indent: usize, words: &[&str]) -> usize {
+    let characters: usize = words.iter().map(|word| word.chars().count()).sum();
+    indent * TAB_WIDTH + characters + words.len().saturating_sub(1)
+}
+
+// A single-line comment at the end of a line of code, which lines up with the ones around it.
+// Comments after @labels are left where they are, they're usually the routine's signature
+fn has_trailing_comment(line: &[Lexeme]) -> bool {
+    match line.split_last() {
+        Some((last, rest)) => {
+            is_comment(last)
+                && rest.iter().any(|lexeme| !is_comment(lexeme))
+                && !line.iter().any(|lexeme| lexeme.value().contains('\n'))
+                && !starts_with(&line[0], &['@'])
+        }
+        None => false,
+    }
+}
+
+// Splits a line of nothing but data that's too long into rows that fit, with any labels at the
+// start kept on the first row
+fn wrap(line: &[Lexeme], placement: Placement) -> Option<Vec<(usize, Vec<&str>)>> {
+    let words: Vec<&str> = line.iter().map(|lexeme| lexeme.value()).collect();
+    let header = line
+        .iter()
+        .take_while(|lexeme| starts_with(lexeme, &['@', '&']))
+        .count();
+    let data = &line[header..];
+    if data.is_empty() || !data.iter().all(is_data) || width(placement.indent, &words) <= MAX_WIDTH
+    {
+        return None;
+    }
+
+    let mut rows = vec![];
+    let mut indent = placement.indent;
+    let mut row = words[..header].to_vec();
+    let mut has_data = false;
+    for word in &words[header..] {
+        if has_data && width(indent, &row) + 1 + word.chars().count() > MAX_WIDTH {
+            rows.push((indent, row));
+            indent = placement.after;
+            row = vec![];
+        }
+        row.push(*word);
+        has_data = true;
+    }
+    rows.push((indent, row));
+    Some(rows)
+}
+
+fn indented(indent: usize, text: &str) -> String {
+    format!("{}{}", INDENT.repeat(indent), text)
+}
+
+// Rewrites source with consistent whitespace, leaving everything the assembler sees alone, so it
+// assembles to the same ROM. Formatting the result again doesn't change it
+pub fn format_source(source: &str, file: PathBuf) -> Result<String, Error> {
+    let lines = split_lines(lex(source, file)?);
+    let placements = place(&lines);
+
+    let mut output: Vec<String> = vec![];
+    let mut i = 0;
+    while i < lines.len() {
+        let line = &lines[i];
+        if line.is_empty() {
+            // Runs of blank lines become one, and there are none at the start
+            if output.last().is_some_and(|last| !last.is_empty()) {
+                output.push(String::new());
+            }
+            i += 1;
+            continue;
+        }
+
+        if has_trailing_comment(line) {
+            let indent = placements[i].indent;
+            let mut end = i;
+            while end < lines.len()
+                && has_trailing_comment(&lines[end])
+                && placements[end].indent == indent
+            {
+                end += 1;
+            }
+            let codes: Vec<String> = lines[i..end]
+                .iter()
+                .map(|line| {
+                    let words: Vec<&str> = line[..line.len() - 1]
+                        .iter()
+                        .map(|lexeme| lexeme.value())
+                        .collect();
+                    words.join(" ")
+                })
+                .collect();
+            let column = codes.iter().map(|code| code.chars().count()).max().unwrap();
+            for (line, code) in lines[i..end].iter().zip(codes) {
+                let padding = " ".repeat(column - code.chars().count() + 1);
+                let comment = line.last().unwrap().value();
+                output.push(indented(indent, &format!("{code}{padding}{comment}")));
+            }
+            i = end;
+            continue;
+        }
+
+        match wrap(line, placements[i]) {
+            Some(rows) => {
+                for (indent, row) in rows {
+                    output.push(indented(indent, &row.join(" ")));
+                }
+            }
+            None => {
+                let words: Vec<&str> = line.iter().map(|lexeme| lexeme.value()).collect();
+                output.push(indented(placements[i].indent, &words.join(" ")));
+            }
+        }
+        i += 1;
+    }
+
+    while output.last().is_some_and(|last| last.is_empty()) {
+        output.pop();
+    }
+    if output.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(output.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+    use crate::Options;
+    use std::env::current_dir;
+    use std::fs::read_to_string;
+    use std::path::Path;
+
+    fn format(source: &str) -> String {
+        format_source(source, PathBuf::from("foo.tal")).unwrap()
+    }
+
+    #[test]
+    fn it_indents_under_labels() {
+        let source = "|0100\n@main ( -> )\n#01 #02 ADD\n    &loop\nINC DUP ?&loop\nBRK\n@next BRK";
+        let expected = "\
+|0100
+@main ( -> )
+\t#01 #02 ADD
+\t&loop
+\t\tINC DUP ?&loop
+\t\tBRK
+@next BRK
+";
+        assert_eq!(format(source), expected);
+    }
+
+    #[test]
+    fn it_indents_inside_braces() {
+        let source = "@main\n#01 ?{\n#02\n}\n%MACRO {\nDUP\n}\nMACRO";
+        let expected = "\
+@main
+\t#01 ?{
+\t\t#02
+\t}
+%MACRO {
+\tDUP
+}
+MACRO
+";
+        assert_eq!(format(source), expected);
+    }
+
+    #[test]
+    fn it_aligns_comments() {
+        let source = "@main\n#01 ( one )\n#0203    ( two )\n\n#04 ( four )\n#05\n#06 ( six )";
+        let expected = "\
+@main
+\t#01   ( one )
+\t#0203 ( two )
+
+\t#04 ( four )
+\t#05
+\t#06 ( six )
+";
+        assert_eq!(format(source), expected);
+    }
+
+    #[test]
+    fn comments_are_indented_like_the_line_below() {
+        let source = "@a\nBRK\n( about b )\n  ( more about b )\n@b\nBRK\n( end of b )\n\n@c";
+        let expected = "\
+@a
+\tBRK
+( about b )
+( more about b )
+@b
+\tBRK
+\t( end of b )
+
+@c
+";
+        assert_eq!(format(source), expected);
+    }
+
+    #[test]
+    fn it_leaves_multi_line_comments_alone() {
+        let source = "( one\n   two )\n@main ( a\n  b ) BRK";
+        assert_eq!(format(source), source.to_string() + "\n");
+    }
+
+    #[test]
+    fn it_wraps_long_data() {
+        let bytes: Vec<String> = (0..30).map(|byte| format!("{byte:02x}")).collect();
+        let source = format!("@table {}\n&text \"hello \"world", bytes.join(" "));
+        let expected = "\
+@table 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f 10 11 12 13 14 15 16 17
+\t18 19 1a 1b 1c 1d
+\t&text \"hello \"world
+";
+        assert_eq!(format(&source), expected);
+    }
+
+    #[test]
+    fn it_tidies_whitespace() {
+        let source = "\r\n\r\n|0100  #01\t\r\n\r\n\r\n\r\nBRK  \r\n\r\n";
+        assert_eq!(format(source), "|0100 #01\n\n\tBRK\n");
+        assert_eq!(format(" \n\n"), "");
+    }
+
+    #[test]
+    fn unclosed_comments_fail() {
+        let error = format_source("( BRK", PathBuf::from("foo.tal")).unwrap_err();
+        assert_eq!(error.code(), Some("E0002"));
+    }
+
+    #[test]
+    fn it_is_idempotent_and_keeps_roms_the_same() {
+        let cwd = current_dir().unwrap().join("tests/roms");
+        let mut directories = vec![cwd.clone()];
+        while let Some(directory) = directories.pop() {
+            for entry in directory.read_dir().unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+                if path.extension().unwrap_or_default() != "tal" {
+                    continue;
+                }
+                let source = read_to_string(&path).unwrap();
+                let formatted = format_source(&source, path.clone()).unwrap();
+                assert_eq!(format_source(&formatted, path.clone()).unwrap(), formatted);
+
+                if !path.with_extension("rom").exists() {
+                    continue;
+                }
+                let options = Options {
+                    cwd: cwd.clone(),
+                    file: path
+                        .strip_prefix(&cwd)
+                        .unwrap_or(Path::new(""))
+                        .to_path_buf(),
+                    no_trim: true,
+                    ..Options::default()
+                };
+                let expected = assemble(&source, &options).unwrap();
+                let actual = assemble(&formatted, &options).unwrap();
+                assert_eq!(actual.rom.get_bytes(), expected.rom.get_bytes(), "{path:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::fs::read;
+use std::path::Path;
+use std::path::PathBuf;
+
+pub trait IncludeResolver {
+    // Returns the source of the file at path, or a message explaining why it couldn't be read.
+    // Source is bytes so that invalid UTF-8 is reported where it occurs, as it is for the file
+    // being assembled
+    fn resolve(&self, path: &Path) -> Result<Vec<u8>, String>;
+}
+
+// Reads includes from the filesystem, relative to cwd
+#[derive(Debug, Default)]
+pub struct FileSystemResolver {
+    cwd: PathBuf,
+}
+
+impl FileSystemResolver {
+    pub fn new(cwd: PathBuf) -> Self {
+        FileSystemResolver { cwd }
+    }
+}
+
+impl IncludeResolver for FileSystemResolver {
+    fn resolve(&self, path: &Path) -> Result<Vec<u8>, String> {
+        read(self.cwd.join(path)).map_err(|err| err.to_string())
+    }
+}
+
+// Reads includes from a set of in-memory files, for editors and tests
+#[derive(Debug, Default)]
+pub struct MemoryResolver {
+    files: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl MemoryResolver {
+    pub fn new() -> Self {
+        MemoryResolver {
+            files: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, path: PathBuf, source: impl Into<Vec<u8>>) {
+        self.files.insert(path, source.into());
+    }
+}
+
+impl IncludeResolver for MemoryResolver {
+    fn resolve(&self, path: &Path) -> Result<Vec<u8>, String> {
+        match self.files.get(path) {
+            Some(source) => Ok(source.clone()),
+            None => Err("no such file".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::current_dir;
+
+    #[test]
+    fn file_system_resolver_works() {
+        let resolver = FileSystemResolver::new(current_dir().unwrap().join("tests/roms"));
+        let source = resolver.resolve(Path::new("hello.tal")).unwrap();
+        assert!(String::from_utf8(source).unwrap().contains("LIT 68"));
+        assert!(resolver.resolve(Path::new("missing.tal")).is_err());
+    }
+
+    #[test]
+    fn memory_resolver_works() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("lib.tal"), "#12".to_string());
+        assert_eq!(resolver.resolve(Path::new("lib.tal")), Ok(b"#12".to_vec()));
+        assert_eq!(
+            resolver.resolve(Path::new("missing.tal")),
+            Err("no such file".to_string())
+        );
+    }
+}
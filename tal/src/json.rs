@@ -0,0 +1,29 @@
+// Quotes a string for use in JSON output
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for chr in value.chars() {
+        match chr {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            chr if (chr as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", chr as u32)),
+            chr => quoted.push(chr),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(quote("on-reset"), "\"on-reset\"");
+        assert_eq!(quote("a\"b\\c\nd\u{1}"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+        assert_eq!(quote("λ00"), "\"λ00\"");
+    }
+}
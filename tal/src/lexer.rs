@@ -0,0 +1,236 @@
+use crate::chunker::is_whitespace;
+use crate::chunker::Chunk;
+use crate::error::Error;
+use std::path::PathBuf;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LexemeKind {
+    // Spaces, tabs, carriage returns and form feeds
+    Space,
+    Newline,
+    // From ( to the first ) after it, including any newlines in between
+    Comment,
+    // [ and ], which the assembler ignores
+    Bracket,
+    // Anything else, which is what the assembler sees
+    Word,
+}
+
+// A piece of source that the chunks the assembler works with can't represent. Putting the
+// values of every lexeme back together gives the source it came from
+#[derive(Debug, PartialEq, Clone)]
+pub struct Lexeme {
+    pub kind: LexemeKind,
+    pub chunk: Chunk,
+}
+
+impl Lexeme {
+    pub fn value(&self) -> &str {
+        &self.chunk.value
+    }
+}
+
+fn is_whitespace_char(character: char) -> bool {
+    character.is_ascii() && is_whitespace(character as u8)
+}
+
+fn kind_of(character: char) -> LexemeKind {
+    match character {
+        '\n' => LexemeKind::Newline,
+        _ if is_whitespace_char(character) => LexemeKind::Space,
+        _ => LexemeKind::Word,
+    }
+}
+
+// Splits source into words, runs of spaces and newlines
+fn split(source: &str) -> Vec<Lexeme> {
+    let mut lexemes: Vec<Lexeme> = vec![];
+    let mut line = 0;
+    let mut column = 0;
+    for (offset, character) in source.char_indices() {
+        let kind = kind_of(character);
+        match lexemes.last_mut() {
+            Some(last) if last.kind == kind && kind != LexemeKind::Newline => {
+                last.chunk.value.push(character);
+            }
+            _ => lexemes.push(Lexeme {
+                kind,
+                chunk: Chunk::new(character.to_string(), line, column, offset),
+            }),
+        }
+        if kind == LexemeKind::Newline {
+            line += 1;
+            column = 0;
+        } else {
+            column += 1;
+        }
+    }
+    lexemes
+}
+
+// Splits source into lexemes without losing anything, with comments and brackets found the same
+// way the assembler finds them
+pub fn lex(source: &str, file: PathBuf) -> Result<Vec<Lexeme>, Error> {
+    let mut lexemes: Vec<Lexeme> = vec![];
+    let mut comment: Option<Lexeme> = None;
+    for mut lexeme in split(source) {
+        if let Some(open) = &mut comment {
+            open.chunk.value.push_str(lexeme.value());
+            if lexeme.kind == LexemeKind::Word && lexeme.value() == ")" {
+                lexemes.extend(comment.take());
+            }
+            continue;
+        }
+        if lexeme.kind == LexemeKind::Word {
+            match lexeme.value() {
+                "(" => {
+                    lexeme.kind = LexemeKind::Comment;
+                    comment = Some(lexeme);
+                    continue;
+                }
+                "[" | "]" => lexeme.kind = LexemeKind::Bracket,
+                _ => {}
+            }
+        }
+        lexemes.push(lexeme);
+    }
+
+    match comment {
+        Some(open) => {
+            let chunk = Chunk::new(
+                "(".to_string(),
+                open.chunk.line,
+                open.chunk.column,
+                open.chunk.offset,
+            );
+            Err(Error::new(
+                "reached EOF without finding comment close".to_string(),
+                chunk,
+                file,
+            )
+            .with_code("E0002"))
+        }
+        None => Ok(lexemes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::Chunker;
+    use crate::pre_process_brackets::PreProcessBrackets;
+    use crate::pre_process_comments::PreProcessComments;
+    use std::fs::read_to_string;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    fn assert_lexes(source: &str, expected: &[(LexemeKind, &str)]) {
+        let lexemes = lex(source, PathBuf::new()).unwrap();
+        let kinds: Vec<(LexemeKind, &str)> = lexemes
+            .iter()
+            .map(|lexeme| (lexeme.kind, lexeme.value()))
+            .collect();
+        assert_eq!(kinds, expected);
+    }
+
+    #[test]
+    fn it_works() {
+        use LexemeKind::*;
+        assert_lexes(
+            "@main ( a\n b ) [ #01 ]\r\n\n\tBRK",
+            &[
+                (Word, "@main"),
+                (Space, " "),
+                (Comment, "( a\n b )"),
+                (Space, " "),
+                (Bracket, "["),
+                (Space, " "),
+                (Word, "#01"),
+                (Space, " "),
+                (Bracket, "]"),
+                (Space, "\r"),
+                (Newline, "\n"),
+                (Newline, "\n"),
+                (Space, "\t"),
+                (Word, "BRK"),
+            ],
+        );
+    }
+
+    #[test]
+    fn comments_are_found_like_the_assembler_finds_them() {
+        use LexemeKind::*;
+        // They don't nest, and only ( on its own starts one
+        assert_lexes(
+            "( a ( b ) c) (d )",
+            &[
+                (Comment, "( a ( b )"),
+                (Space, " "),
+                (Word, "c)"),
+                (Space, " "),
+                (Word, "(d"),
+                (Space, " "),
+                (Word, ")"),
+            ],
+        );
+    }
+
+    #[test]
+    fn it_keeps_positions() {
+        let lexemes = lex("#01\n  ( é ) BRK", PathBuf::new()).unwrap();
+        let brk = lexemes.last().unwrap();
+        assert_eq!(brk.value(), "BRK");
+        assert_eq!(
+            (brk.chunk.line, brk.chunk.column, brk.chunk.offset),
+            (1, 8, 13)
+        );
+    }
+
+    #[test]
+    fn unclosed_comments_fail() {
+        let error = lex("#01\n ( BRK", PathBuf::from("foo.tal")).unwrap_err();
+        assert_eq!(
+            error,
+            Error::new(
+                "reached EOF without finding comment close".to_string(),
+                Chunk::new("(".to_string(), 1, 1, 5),
+                PathBuf::from("foo.tal"),
+            )
+            .with_code("E0002")
+        );
+    }
+
+    #[test]
+    fn it_is_lossless_and_agrees_with_the_assembler() {
+        let mut directories = vec![Path::new("tests/roms").to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            for entry in directory.read_dir().unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+                if path.extension().unwrap_or_default() != "tal" {
+                    continue;
+                }
+                let source = read_to_string(&path).unwrap();
+                let lexemes = lex(&source, path.clone()).unwrap();
+
+                let joined: String = lexemes.iter().map(|lexeme| lexeme.value()).collect();
+                assert_eq!(joined, source, "{path:?}");
+
+                let words: Vec<&str> = lexemes
+                    .iter()
+                    .filter(|lexeme| lexeme.kind == LexemeKind::Word)
+                    .map(|lexeme| lexeme.value())
+                    .collect();
+                let mut reader = Cursor::new(source.as_bytes());
+                let mut chunker = Chunker::new(path.clone(), &mut reader);
+                let mut brackets = PreProcessBrackets::new(&mut chunker);
+                let comments = PreProcessComments::new(path.clone(), &mut brackets);
+                let chunks: Vec<String> = comments.map(|chunk| chunk.unwrap().value).collect();
+                assert_eq!(words, chunks, "{path:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,265 @@
+mod chunker;
+mod disassembler;
+mod error;
+mod formatter;
+mod include_resolver;
+mod json;
+mod lexer;
+mod listing;
+mod opcode;
+mod output_format;
+#[macro_use]
+mod parser;
+mod pre_process_brackets;
+mod pre_process_comments;
+mod pre_process_includes;
+mod pre_process_macros;
+mod source_map;
+mod stack_effects;
+mod symbols;
+mod token;
+
+pub use crate::chunker::Chunk;
+pub use crate::chunker::Expansion;
+pub use crate::chunker::Include;
+pub use crate::disassembler::disassemble;
+pub use crate::error::Error;
+pub use crate::error::Label;
+pub use crate::error::Severity;
+pub use crate::formatter::format_source;
+pub use crate::include_resolver::FileSystemResolver;
+pub use crate::include_resolver::IncludeResolver;
+pub use crate::include_resolver::MemoryResolver;
+pub use crate::lexer::lex;
+pub use crate::lexer::Lexeme;
+pub use crate::lexer::LexemeKind;
+pub use crate::listing::Listing;
+pub use crate::listing::ListingEntry;
+pub use crate::opcode::Opcode;
+pub use crate::opcode::StackEffect;
+pub use crate::output_format::write_rom;
+pub use crate::output_format::OutputFormat;
+pub use crate::parser::Assembly;
+pub use crate::parser::Rom;
+pub use crate::parser::MAX_ERRORS;
+pub use crate::source_map::write_source_map;
+pub use crate::stack_effects::infer_stack_effects;
+pub use crate::stack_effects::parse_signature;
+pub use crate::stack_effects::Effect;
+pub use crate::stack_effects::Inferred;
+pub use crate::stack_effects::Routine;
+pub use crate::stack_effects::Signature;
+pub use crate::symbols::Symbol;
+pub use crate::symbols::Symbols;
+
+use crate::chunker::Chunker;
+use crate::parser::parse_chunks;
+use crate::parser::ParseOptions;
+use crate::stack_effects::check_stack_effects;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    // Directory that includes are resolved against by assemble
+    pub cwd: PathBuf,
+    // Path of the source being assembled, used in errors
+    pub file: PathBuf,
+    // Warn about labels that are defined but never referenced
+    pub warn_unused: bool,
+    // Keep trailing zeros in the ROM instead of trimming them like uxnasm
+    pub no_trim: bool,
+    // Warn about routines whose bodies don't match their ( a b* -- c ) comments
+    pub check_stack_effects: bool,
+}
+
+pub fn assemble(source: impl AsRef<[u8]>, options: &Options) -> Result<Assembly, Vec<Error>> {
+    let resolver = FileSystemResolver::new(options.cwd.clone());
+    assemble_with_resolver(source, options, &resolver)
+}
+
+// source is bytes rather than a string so that invalid UTF-8 is reported where it occurs
+pub fn assemble_with_resolver(
+    source: impl AsRef<[u8]>,
+    options: &Options,
+    resolver: &dyn IncludeResolver,
+) -> Result<Assembly, Vec<Error>> {
+    let mut reader = Cursor::new(source.as_ref());
+    let mut chunker = Chunker::new(options.file.clone(), &mut reader);
+    let parse_options = ParseOptions {
+        warn_unused: options.warn_unused,
+        no_trim: options.no_trim,
+    };
+    let mut assembly = parse_chunks(resolver, options.file.clone(), &parse_options, &mut chunker)?;
+    if options.check_stack_effects {
+        let source = String::from_utf8_lossy(source.as_ref());
+        let warnings = check_stack_effects(&assembly, &source, &options.file, resolver);
+        assembly.warnings.extend(warnings);
+    }
+    Ok(assembly)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::current_dir;
+    use std::path::Path;
+
+    #[test]
+    fn it_works() {
+        let assembly = assemble("|0100 LIT 68 LIT 18 DEO", &Options::default()).unwrap();
+        assert_eq!(assembly.rom.get_bytes(), [0x80, 0x68, 0x80, 0x18, 0x17]);
+    }
+
+    #[test]
+    fn it_resolves_includes_against_cwd() {
+        let options = Options {
+            cwd: current_dir().unwrap().join("tests/roms"),
+            ..Options::default()
+        };
+        let assembly = assemble("~hello.tal", &options).unwrap();
+        assert_eq!(assembly.rom.get_bytes(), [0x80, 0x68, 0x80, 0x18, 0x17]);
+    }
+
+    #[test]
+    fn it_resolves_includes_from_memory() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("lib.tal"), "@lib #12".to_string());
+        let assembly =
+            assemble_with_resolver("|0100 ~lib.tal", &Options::default(), &resolver).unwrap();
+        assert_eq!(assembly.rom.get_bytes(), [0x80, 0x12]);
+    }
+
+    #[test]
+    fn it_reports_missing_includes() {
+        let options = Options {
+            file: PathBuf::from("foo.tal"),
+            ..Options::default()
+        };
+        let resolver = MemoryResolver::new();
+        let errors = assemble_with_resolver("|0100 ~lib.tal", &options, &resolver).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![Error::new(
+                "could not include \"lib.tal\": no such file".to_string(),
+                Chunk::new("~lib.tal".to_string(), 0, 6, 6),
+                PathBuf::from("foo.tal"),
+            )
+            .with_code("E0003")]
+        );
+    }
+
+    #[test]
+    fn it_returns_errors() {
+        let options = Options {
+            file: PathBuf::from("foo.tal"),
+            ..Options::default()
+        };
+        let errors = assemble("|0100 ;foo", &options).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![Error::new(
+                "unknown name \"foo\"".to_string(),
+                Chunk::new(";foo".to_string(), 0, 6, 6),
+                PathBuf::from("foo.tal"),
+            )
+            .with_code("E0012")]
+        );
+    }
+
+    #[test]
+    fn it_reports_errors_in_included_files() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("lib/a.tal"), "@a\n~b.tal".to_string());
+        resolver.insert(
+            PathBuf::from("lib/b.tal"),
+            "@b\n  ;missing ( open".to_string(),
+        );
+        let options = Options {
+            file: PathBuf::from("main.tal"),
+            ..Options::default()
+        };
+        let source = "|0100\n~lib/a.tal";
+        let errors = assemble_with_resolver(source, &options, &resolver).unwrap_err();
+
+        let files: Vec<&Path> = errors.iter().map(|err| err.file()).collect();
+        assert_eq!(files, [Path::new("lib/b.tal"), Path::new("lib/b.tal")]);
+
+        let sources = |path: &Path| match resolver.resolve(path) {
+            Ok(source) => source,
+            Err(_) => source.as_bytes().to_vec(),
+        };
+        let expected = "\
+lib/b.tal:2: Error[E0002]: reached EOF without finding comment close
+
+  ;missing ( open
+           ^
+
+included from lib/a.tal:2
+included from main.tal:2";
+        assert_eq!(errors[0].render(&sources, false), expected);
+        assert_eq!(errors[1].message(), "unknown name \"missing\"");
+        assert_eq!(errors[1].included_from().len(), 2);
+    }
+
+    #[test]
+    fn it_reports_errors_in_macro_expansions() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("lib.tal"), "@lib TWICE".to_string());
+        let options = Options {
+            file: PathBuf::from("main.tal"),
+            ..Options::default()
+        };
+        let source = "%EMIT { #1g8 DEO }\n%TWICE { EMIT EMIT }\n|0100 ~lib.tal";
+        let errors = assemble_with_resolver(source, &options, &resolver).unwrap_err();
+        assert_eq!(errors.len(), 2);
+
+        let sources = |path: &Path| match resolver.resolve(path) {
+            Ok(source) => source,
+            Err(_) => source.as_bytes().to_vec(),
+        };
+        let expected = "\
+main.tal:1: Error[E0009]: could not parse byte or short
+
+%EMIT { #1g8 DEO }
+        ^^^^
+
+in expansion of EMIT at main.tal:2
+in expansion of TWICE at lib.tal:1";
+        assert_eq!(errors[0].render(&sources, false), expected);
+        assert!(errors[0].to_json().contains(r#""expansion": [{"macro": "EMIT", "file": "main.tal", "line": 2, "column": 10, "offset": 28, "length": 4}, {"macro": "TWICE", "file": "lib.tal", "line": 1, "column": 6, "offset": 5, "length": 5}]"#));
+    }
+
+    #[test]
+    fn it_reports_invalid_utf8() {
+        let options = Options {
+            file: PathBuf::from("foo.tal"),
+            ..Options::default()
+        };
+        let errors = assemble(b"|0100 #12\r\n\xff BRK", &options).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "invalid UTF-8");
+        assert_eq!(errors[0].chunk().line, 1);
+        assert_eq!(errors[0].chunk().offset, 11);
+        assert_eq!(errors[0].file(), Path::new("foo.tal"));
+    }
+
+    #[test]
+    fn it_reports_invalid_utf8_in_included_files() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("lib/u.tal"), b"@u #12\n  \xff BRK".to_vec());
+        let options = Options {
+            file: PathBuf::from("main.tal"),
+            ..Options::default()
+        };
+        let errors = assemble_with_resolver("|0100 ~lib/u.tal", &options, &resolver).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "invalid UTF-8");
+        assert_eq!(errors[0].code(), Some("E0001"));
+        assert_eq!(errors[0].file(), Path::new("lib/u.tal"));
+        assert_eq!(errors[0].chunk().line, 1);
+        assert_eq!(errors[0].chunk().column, 2);
+        assert_eq!(errors[0].chunk().offset, 9);
+        assert_eq!(errors[0].included_from().len(), 1);
+    }
+}
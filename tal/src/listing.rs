@@ -0,0 +1,176 @@
+use crate::chunker::Chunk;
+use crate::parser::Rom;
+use std::io::Write;
+use std::path::Path;
+
+const BYTES_PER_LINE: usize = 8;
+
+// A token and where it ended up in the ROM
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListingEntry {
+    // Where the token's bytes start, or where padding moved to
+    pub address: u16,
+    // How many bytes the token wrote
+    pub length: u16,
+    // The full name of the label the token defined, if it's a label
+    pub label: Option<String>,
+    // The parent label in scope when the token was assembled
+    pub parent: Option<String>,
+    pub chunk: Chunk,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Listing {
+    entries: Vec<ListingEntry>,
+}
+
+fn location(chunk: &Chunk, file: &Path) -> String {
+    let mut location = format!("{}:{}", chunk.file(file).display(), chunk.line + 1);
+    let mut expansion = &chunk.expansion;
+    while let Some(frame) = expansion {
+        location.push_str(&format!(
+            " [{} at {}:{}]",
+            frame.name,
+            frame.chunk.file(file).display(),
+            frame.chunk.line + 1
+        ));
+        expansion = &frame.chunk.expansion;
+    }
+    location
+}
+
+fn hex_row(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    hex.join(" ")
+}
+
+impl Listing {
+    pub fn new() -> Self {
+        Listing { entries: vec![] }
+    }
+
+    pub fn push(&mut self, entry: ListingEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ListingEntry> {
+        self.entries.iter()
+    }
+
+    // One line per token with its address, the bytes it ended up as and where it came from,
+    // labels as headers, and a note whenever the listing moves into or out of an include
+    pub fn write(&self, rom: &Rom, file: &Path, writer: &mut dyn Write) -> std::io::Result<()> {
+        let bytes = rom.get_written_bytes();
+        let mut current_file = None;
+
+        for entry in &self.entries {
+            let entry_file = entry.chunk.file(file);
+            if current_file != Some(entry_file) {
+                match &entry.chunk.include {
+                    Some(include) => {
+                        let (from, chunk) = include.included_from.last().unwrap();
+                        writeln!(
+                            writer,
+                            "( {} included from {}:{} )",
+                            entry_file.display(),
+                            from.display(),
+                            chunk.line + 1
+                        )?;
+                    }
+                    None => writeln!(writer, "( {} )", entry_file.display())?,
+                }
+                current_file = Some(entry_file);
+            }
+
+            if let Some(name) = &entry.label {
+                if !name.contains('/') {
+                    writeln!(writer)?;
+                }
+                writeln!(writer, "{:04x}  {name}:", entry.address)?;
+                continue;
+            }
+
+            let start = entry.address as usize;
+            let written = if entry.length == 0 {
+                &[]
+            } else {
+                &bytes[start - 0x100..start - 0x100 + entry.length as usize]
+            };
+            let mut rows = written.chunks(BYTES_PER_LINE);
+            writeln!(
+                writer,
+                "{:04x}  {:<23}  {:<20}  {}",
+                entry.address,
+                hex_row(rows.next().unwrap_or_default()),
+                entry.chunk.value,
+                location(&entry.chunk, file)
+            )?;
+            for (i, row) in rows.enumerate() {
+                let address = start + (i + 1) * BYTES_PER_LINE;
+                writeln!(writer, "{:04x}  {}", address, hex_row(row))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunker::Chunker;
+    use crate::include_resolver::MemoryResolver;
+    use crate::parser::parse_chunks;
+    use crate::parser::ParseOptions;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    fn listing(source: &str, resolver: &MemoryResolver) -> String {
+        let mut reader = Cursor::new(source);
+        let mut chunker = Chunker::new(PathBuf::new(), &mut reader);
+        let file = PathBuf::from("main.tal");
+        let options = ParseOptions::default();
+        let assembly = parse_chunks(resolver, file.clone(), &options, &mut chunker).unwrap();
+
+        let mut output = vec![];
+        assembly
+            .listing
+            .write(&assembly.rom, &file, &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn it_works() {
+        let source = "|0100 @on-reset\n;on-reset/data JMP2r\n&data \"hello-world-!";
+        let expected = "\
+( main.tal )
+0100                           |0100                 main.tal:1
+
+0100  on-reset:
+0100  a0 01 04                 ;on-reset/data        main.tal:2
+0103  6c                       JMP2r                 main.tal:2
+0104  on-reset/data:
+0104  68 65 6c 6c 6f 2d 77 6f  \"hello-world-!        main.tal:3
+010c  72 6c 64 2d 21
+";
+        assert_eq!(listing(source, &MemoryResolver::new()), expected);
+    }
+
+    #[test]
+    fn it_marks_macros_and_includes() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("lib.tal"), "\n#01".to_string());
+        let source = "%EMIT { #18 DEO }\n%TWICE { EMIT EMIT }\n|0100 ~lib.tal\nTWICE";
+        let expected = "\
+( main.tal )
+0100                           |0100                 main.tal:3
+( lib.tal included from main.tal:3 )
+0100  80 01                    #01                   lib.tal:2
+( main.tal )
+0102  80 18                    #18                   main.tal:1 [EMIT at main.tal:2] [TWICE at main.tal:4]
+0104  17                       DEO                   main.tal:1 [EMIT at main.tal:2] [TWICE at main.tal:4]
+0105  80 18                    #18                   main.tal:1 [EMIT at main.tal:2] [TWICE at main.tal:4]
+0107  17                       DEO                   main.tal:1 [EMIT at main.tal:2] [TWICE at main.tal:4]
+";
+        assert_eq!(listing(source, &resolver), expected);
+    }
+}
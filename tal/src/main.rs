@@ -1,69 +1,382 @@
-mod chunker;
-mod error;
-mod opcode;
-#[macro_use]
-mod parser;
-mod pre_process_brackets;
-mod pre_process_comments;
-mod pre_process_includes;
-mod pre_process_macros;
-mod token;
-
-use crate::chunker::Chunk;
-use crate::error::Error;
-use crate::parser::chunk_file;
-use crate::parser::parse_chunks;
 use std::env::args;
 use std::env::current_dir;
+use std::fs::read;
 use std::fs::File;
 use std::fs::OpenOptions;
-use std::io::BufReader;
+use std::io::stdout;
+use std::io::IsTerminal;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::exit;
+use std::str::FromStr;
+use tal::assemble;
+use tal::assemble_with_resolver;
+use tal::disassemble;
+use tal::format_source;
+use tal::infer_stack_effects;
+use tal::write_rom;
+use tal::write_source_map;
+use tal::Assembly;
+use tal::Error;
+use tal::FileSystemResolver;
+use tal::Options;
+use tal::OutputFormat;
+use tal::Symbols;
+use tal::MAX_ERRORS;
+
+// The name C and Rust output is declared under, taken from the input file
+fn output_name(options: &Options) -> String {
+    match options.file.file_stem() {
+        Some(stem) => stem.to_string_lossy().to_string(),
+        None => "rom".to_string(),
+    }
+}
 
 fn read_and_write(
-    cwd: &Path,
-    file: PathBuf,
+    source: &[u8],
+    options: &Options,
+    format: OutputFormat,
     writer: &mut dyn Write,
-    chunker: Vec<Result<Chunk, Error>>,
-) -> Result<(), Error> {
-    match parse_chunks(cwd, file, &mut chunker.into_iter()) {
-        Ok(rom) => match writer.write_all(rom.get_bytes()) {
-            Ok(_) => Ok(()),
-            Err(err) => panic!("{:?}", err),
+) -> Result<Assembly, Vec<Error>> {
+    let assembly = assemble(source, options)?;
+    let bytes = assembly.rom.get_bytes();
+    let name = output_name(options);
+    match write_rom(bytes, &assembly.symbols, format, &name, writer) {
+        Ok(_) => Ok(assembly),
+        Err(err) => panic!("{:?}", err),
+    }
+}
+
+fn create_file(path: &str) -> File {
+    OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(path)
+        .unwrap()
+}
+
+fn write_symbols(symbols: &Symbols, output_path: &str, sym_text_path: Option<&str>) {
+    let mut output = create_file(&format!("{output_path}.sym"));
+    symbols.write_binary(&mut output).unwrap();
+
+    if let Some(path) = sym_text_path {
+        let mut output = create_file(path);
+        symbols.write_text(&mut output).unwrap();
+    }
+}
+
+// Prints an error or warning, either for people or as a line of JSON for tools. Lines are
+// quoted from source when they're in the input file, and read from disk when they're in an
+// included one
+fn print_diagnostic(diagnostic: &Error, source: &[u8], options: &Options, json: bool) {
+    if json {
+        println!("{}", diagnostic.to_json());
+    } else {
+        let colour = std::io::stdout().is_terminal();
+        let sources = |path: &Path| {
+            if path == options.file {
+                source.to_vec()
+            } else {
+                read(options.cwd.join(path)).unwrap_or_default()
+            }
+        };
+        println!("{}\n", diagnostic.render(&sources, colour));
+    }
+}
+
+struct Args {
+    input_path: String,
+    output_path: String,
+    sym_text_path: Option<String>,
+    listing_path: Option<String>,
+    source_map_path: Option<String>,
+    format: OutputFormat,
+    warn_unused: bool,
+    no_trim: bool,
+    check_stack_effects: bool,
+    json_messages: bool,
+}
+
+fn parse_args(args: &mut dyn Iterator<Item = String>) -> Option<Args> {
+    let mut paths = vec![];
+    let mut sym_text_path = None;
+    let mut listing_path = None;
+    let mut source_map_path = None;
+    let mut format = OutputFormat::Rom;
+    let mut warn_unused = false;
+    let mut no_trim = false;
+    let mut check_stack_effects = false;
+    let mut json_messages = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sym-text" => sym_text_path = Some(args.next()?),
+            "--listing" => listing_path = Some(args.next()?),
+            "--source-map" => source_map_path = Some(args.next()?),
+            "--format" => format = OutputFormat::from_str(&args.next()?).ok()?,
+            "--warn-unused" => warn_unused = true,
+            "--no-trim" => no_trim = true,
+            "--check-stack-effects" => check_stack_effects = true,
+            "--message-format" => {
+                json_messages = match args.next()?.as_str() {
+                    "human" => false,
+                    "json" => true,
+                    _ => return None,
+                }
+            }
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.len() != 2 {
+        return None;
+    }
+    let output_path = paths.pop().unwrap();
+    let input_path = paths.pop().unwrap();
+
+    Some(Args {
+        input_path,
+        output_path,
+        sym_text_path,
+        listing_path,
+        source_map_path,
+        format,
+        warn_unused,
+        no_trim,
+        check_stack_effects,
+        json_messages,
+    })
+}
+
+fn usage(program: &str) -> ! {
+    println!(
+        "Usage: {} [--sym-text output.sym.txt] [--listing output.lst] [--source-map output.map.json] [--format rom|ihex|c|rust|hex|base64] [--warn-unused] [--no-trim] [--check-stack-effects] [--message-format human|json] input.tal output.rom",
+        program
+    );
+    println!("       {program} disasm [--sym input.rom.sym] input.rom [output.tal]");
+    println!("       {program} fmt [--check] input.tal [output.tal]");
+    println!("       {program} analyze --stack-effects input.tal");
+    exit(1);
+}
+
+fn read_or_exit(path: &str) -> Vec<u8> {
+    match read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            println!("{path}: Error: {err}");
+            exit(1);
+        }
+    }
+}
+
+// Writes the ROM as uxntal to output.tal, or stdout without it. Labels come from --sym, or from
+// input.rom.sym next to the ROM when there is one
+fn disasm(program: &str, args: &[String]) {
+    let (sym_path, args) = match args {
+        [flag, path, rest @ ..] if flag == "--sym" => (Some(path.clone()), rest),
+        _ => (None, args),
+    };
+    let (input_path, output_path) = match args {
+        [input_path] => (input_path, None),
+        [input_path, output_path] => (input_path, Some(output_path)),
+        _ => usage(program),
+    };
+
+    let rom = read_or_exit(input_path);
+    let default_sym_path = format!("{input_path}.sym");
+    let sym_path = match sym_path {
+        Some(path) => Some(path),
+        None if Path::new(&default_sym_path).exists() => Some(default_sym_path),
+        None => None,
+    };
+    let symbols = match sym_path {
+        Some(path) => match Symbols::read_binary(&read_or_exit(&path)) {
+            Ok(symbols) => symbols,
+            Err(err) => {
+                println!("{path}: Error: {err}");
+                exit(1);
+            }
         },
-        Err(err) => Err(err),
+        None => Symbols::new(),
+    };
+
+    let result = match output_path {
+        Some(path) => disassemble(&rom, &symbols, &mut create_file(path)),
+        None => disassemble(&rom, &symbols, &mut stdout()),
+    };
+    result.unwrap();
+}
+
+// Writes the formatted source to output.tal, or stdout without it. With --check nothing is
+// written, and it fails if the source isn't already formatted
+fn fmt(program: &str, args: &[String]) {
+    let (check, args) = match args {
+        [flag, rest @ ..] if flag == "--check" => (true, rest),
+        _ => (false, args),
+    };
+    let (input_path, output_path) = match args {
+        [input_path] => (input_path, None),
+        [input_path, output_path] if !check => (input_path, Some(output_path)),
+        _ => usage(program),
+    };
+
+    let source = match String::from_utf8(read_or_exit(input_path)) {
+        Ok(source) => source,
+        Err(_) => {
+            println!("{input_path}: Error: invalid UTF-8");
+            exit(1);
+        }
+    };
+    let options = Options {
+        cwd: current_dir().unwrap(),
+        file: PathBuf::from(input_path),
+        ..Options::default()
+    };
+    let formatted = match format_source(&source, options.file.clone()) {
+        Ok(formatted) => formatted,
+        Err(err) => {
+            print_diagnostic(&err, source.as_bytes(), &options, false);
+            exit(1);
+        }
+    };
+
+    if check {
+        if formatted != source {
+            println!("{input_path}: not formatted");
+            exit(1);
+        }
+        return;
+    }
+    match output_path {
+        Some(path) => create_file(path).write_all(formatted.as_bytes()),
+        None => stdout().write_all(formatted.as_bytes()),
+    }
+    .unwrap();
+}
+
+// Prints what each routine does to the stacks, in bytes, with its signature when it has one
+fn analyze(program: &str, args: &[String]) {
+    let input_path = match args {
+        [flag, input_path] if flag == "--stack-effects" => input_path,
+        _ => usage(program),
+    };
+
+    let source = read_or_exit(input_path);
+    let options = Options {
+        cwd: current_dir().unwrap(),
+        file: PathBuf::from(input_path),
+        ..Options::default()
+    };
+    let resolver = FileSystemResolver::new(options.cwd.clone());
+    let assembly = match assemble_with_resolver(&source, &options, &resolver) {
+        Ok(assembly) => assembly,
+        Err(errors) => {
+            for err in &errors {
+                print_diagnostic(err, &source, &options, false);
+            }
+            exit(1);
+        }
+    };
+
+    let text = String::from_utf8_lossy(&source);
+    let routines = infer_stack_effects(&assembly, &text, &options.file, &resolver);
+    for (routine, inferred) in routines {
+        match routine.signature {
+            Some(signature) => println!(
+                "@{} {inferred} declared ( {} )",
+                routine.name, signature.text
+            ),
+            None => println!("@{} {inferred}", routine.name),
+        }
     }
 }
 
 fn main() {
     let mut args = args();
     let program = args.next().unwrap();
+    let args: Vec<String> = args.collect();
 
-    if args.len() != 2 {
-        println!("Usage: {} input.tal output.rom", program);
-        exit(1);
+    if args.first().map(String::as_str) == Some("disasm") {
+        disasm(&program, &args[1..]);
+        return;
+    }
+    if args.first().map(String::as_str) == Some("fmt") {
+        fmt(&program, &args[1..]);
+        return;
+    }
+    if args.first().map(String::as_str) == Some("analyze") {
+        analyze(&program, &args[1..]);
+        return;
     }
-    let cwd = current_dir().unwrap();
-    let input_path = args.next().unwrap();
-    let input_path = Path::new(&input_path);
-    let output_path = args.next().unwrap();
 
-    let mut output = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(output_path)
-        .unwrap();
-    let chunks = chunk_file(&cwd, input_path);
-    let result = read_and_write(&cwd, input_path.to_path_buf(), &mut output, chunks);
-    match result {
-        Ok(_) => println!("OK"),
+    let args = match parse_args(&mut args.into_iter()) {
+        Some(args) => args,
+        None => usage(&program),
+    };
+
+    let source = match read(&args.input_path) {
+        Ok(source) => source,
         Err(err) => {
-            let mut input = BufReader::new(File::open(input_path).unwrap());
-            println!("{}", err.to_string_with_context(&mut input));
+            println!("{}: Error: {}", args.input_path, err);
+            exit(1);
+        }
+    };
+    let options = Options {
+        cwd: current_dir().unwrap(),
+        file: PathBuf::from(&args.input_path),
+        warn_unused: args.warn_unused,
+        no_trim: args.no_trim,
+        check_stack_effects: args.check_stack_effects,
+    };
+
+    let mut output = create_file(&args.output_path);
+    let result = read_and_write(&source, &options, args.format, &mut output);
+    match result {
+        Ok(assembly) => {
+            for warning in &assembly.warnings {
+                print_diagnostic(warning, &source, &options, args.json_messages);
+            }
+            write_symbols(
+                &assembly.symbols,
+                &args.output_path,
+                args.sym_text_path.as_deref(),
+            );
+            if let Some(path) = &args.listing_path {
+                let mut output = create_file(path);
+                assembly
+                    .listing
+                    .write(&assembly.rom, &options.file, &mut output)
+                    .unwrap();
+            }
+            if let Some(path) = &args.source_map_path {
+                let mut output = create_file(path);
+                write_source_map(&assembly.listing, &options.file, &mut output).unwrap();
+            }
+            if !args.json_messages {
+                match assembly.trimmed {
+                    0 => {}
+                    1 => println!("Trimmed 1 trailing zero byte"),
+                    trimmed => println!("Trimmed {trimmed} trailing zero bytes"),
+                }
+                println!("OK");
+            }
+        }
+        Err(errors) => {
+            // An error past MAX_ERRORS only shows that there were more
+            let stopped = errors.len() > MAX_ERRORS;
+            for err in errors.iter().take(MAX_ERRORS) {
+                print_diagnostic(err, &source, &options, args.json_messages);
+            }
+            if args.json_messages {
+                exit(1);
+            }
+            if stopped {
+                println!("Stopped after {MAX_ERRORS} errors");
+            } else if errors.len() > 1 {
+                println!("{} errors", errors.len());
+            }
             exit(1);
         }
     }
@@ -72,19 +385,19 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::chunker::Chunker;
-    use crate::parser::pre_process;
     use std::io::Cursor;
 
     #[test]
     fn it_works() {
-        let mut input = Cursor::new(b"|0100 LIT 68 LIT 18 DEO");
         let mut output = Cursor::<Vec<u8>>::new(vec![]);
         let expected: Vec<u8> = vec![0x80, 0x68, 0x80, 0x18, 0x17];
 
-        let mut chunker = Chunker::new(&mut input);
-        let chunks = pre_process(Path::new(""), PathBuf::new(), &mut chunker);
-        let result = read_and_write(Path::new(""), PathBuf::new(), &mut output, chunks);
+        let result = read_and_write(
+            b"|0100 LIT 68 LIT 18 DEO",
+            &Options::default(),
+            OutputFormat::Rom,
+            &mut output,
+        );
         assert!(result.is_ok());
         println!("{output:?}");
         let actual = output.into_inner();
@@ -1,3 +1,7 @@
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::str::FromStr;
+
 #[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Debug)]
 pub enum Opcode {
@@ -42,6 +46,55 @@ pub enum Opcode {
     SFT(bool, bool, bool),
 }
 
+// What an opcode does to the stacks, in bytes. It takes from and gives to its own stack, which
+// is the return stack in return mode, and JSR and STH give to the other one. In keep mode what it
+// takes is left where it was
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StackEffect {
+    pub takes: usize,
+    pub gives: usize,
+    pub other: usize,
+    pub keep: bool,
+    pub return_stack: bool,
+}
+
+type WithModifiers = fn(bool, bool, bool) -> Opcode;
+
+// The opcodes that take 2, k and r, in byte order starting from 0x01
+const OPCODES: [(&str, WithModifiers); 31] = [
+    ("INC", Opcode::INC),
+    ("POP", Opcode::POP),
+    ("NIP", Opcode::NIP),
+    ("SWP", Opcode::SWP),
+    ("ROT", Opcode::ROT),
+    ("DUP", Opcode::DUP),
+    ("OVR", Opcode::OVR),
+    ("EQU", Opcode::EQU),
+    ("NEQ", Opcode::NEQ),
+    ("GTH", Opcode::GTH),
+    ("LTH", Opcode::LTH),
+    ("JMP", Opcode::JMP),
+    ("JCN", Opcode::JCN),
+    ("JSR", Opcode::JSR),
+    ("STH", Opcode::STH),
+    ("LDZ", Opcode::LDZ),
+    ("STZ", Opcode::STZ),
+    ("LDR", Opcode::LDR),
+    ("STR", Opcode::STR),
+    ("LDA", Opcode::LDA),
+    ("STA", Opcode::STA),
+    ("DEI", Opcode::DEI),
+    ("DEO", Opcode::DEO),
+    ("ADD", Opcode::ADD),
+    ("SUB", Opcode::SUB),
+    ("MUL", Opcode::MUL),
+    ("DIV", Opcode::DIV),
+    ("AND", Opcode::AND),
+    ("ORA", Opcode::ORA),
+    ("EOR", Opcode::EOR),
+    ("SFT", Opcode::SFT),
+];
+
 fn parse_modifiers(s: &str) -> Result<(bool, bool, bool), String> {
     if s.contains(|chr| chr != '2' && chr != 'k' && chr != 'r') {
         Err(format!("valid opcode provided invalid modifiers \"{}\"", s))
@@ -79,9 +132,12 @@ macro_rules! with_2kr {
     }};
 }
 
-impl Opcode {
-    pub fn from_str(s: &str) -> Result<Opcode, String> {
-        if s.len() < 3 {
+impl FromStr for Opcode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Opcode, String> {
+        // Opcode names are ASCII, so anything else can't be one and can't be split at 3 bytes
+        if s.len() < 3 || !s.is_ascii() {
             return Err(format!("unknown opcode \"{}\"", s));
         }
         let name = &s[..3];
@@ -128,6 +184,109 @@ impl Opcode {
             _ => Err(format!("unknown opcode \"{}\"", name)),
         }
     }
+}
+
+// The opcode as it's written in uxntal, e.g. ADD2k
+impl Display for Opcode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let byte = self.as_byte();
+        let name = match byte {
+            0x00 => return f.write_str("BRK"),
+            0x20 => return f.write_str("JCI"),
+            0x40 => return f.write_str("JMI"),
+            0x60 => return f.write_str("JSI"),
+            byte if byte & 0x1f == 0 => "LIT",
+            byte => OPCODES[(byte & 0x1f) as usize - 1].0,
+        };
+        f.write_str(name)?;
+        if byte & 0x20 != 0 {
+            f.write_str("2")?;
+        }
+        // LIT is the keep version of BRK, so it never shows k
+        if byte & 0x80 != 0 && name != "LIT" {
+            f.write_str("k")?;
+        }
+        if byte & 0x40 != 0 {
+            f.write_str("r")?;
+        }
+        Ok(())
+    }
+}
+
+impl Opcode {
+    // Every byte is an opcode, so this is the reverse of as_byte
+    pub fn from_byte(byte: u8) -> Opcode {
+        let two = byte & 0x20 != 0;
+        let return_stack = byte & 0x40 != 0;
+        let keep = byte & 0x80 != 0;
+        match (byte & 0x1f, byte) {
+            (0, 0x00) => Opcode::BRK,
+            (0, 0x20) => Opcode::JCI,
+            (0, 0x40) => Opcode::JMI,
+            (0, 0x60) => Opcode::JSI,
+            (0, _) => Opcode::LIT(two, return_stack),
+            (index, _) => OPCODES[index as usize - 1].1(two, keep, return_stack),
+        }
+    }
+
+    // 2, k and r, all false for the opcodes that don't take them
+    pub fn modes(&self) -> (bool, bool, bool) {
+        match self {
+            Opcode::BRK | Opcode::JCI | Opcode::JMI | Opcode::JSI => (false, false, false),
+            Opcode::LIT(two, return_stack) => (*two, false, *return_stack),
+            _ => {
+                let byte = self.as_byte();
+                (byte & 0x20 != 0, byte & 0x80 != 0, byte & 0x40 != 0)
+            }
+        }
+    }
+
+    pub fn stack_effect(&self) -> StackEffect {
+        let (two, keep, return_stack) = self.modes();
+        let width = if two { 2 } else { 1 };
+        let (takes, gives, other) = match self {
+            Opcode::BRK | Opcode::JMI => (0, 0, 0),
+            Opcode::JCI => (1, 0, 0),
+            // The return address goes on the return stack
+            Opcode::JSI => (0, 0, 2),
+            // The value comes from the bytes after the opcode
+            Opcode::LIT(_, _) => (0, width, 0),
+            Opcode::INC(_, _, _) => (width, width, 0),
+            Opcode::POP(_, _, _) => (width, 0, 0),
+            Opcode::NIP(_, _, _) => (2 * width, width, 0),
+            Opcode::SWP(_, _, _) => (2 * width, 2 * width, 0),
+            Opcode::ROT(_, _, _) => (3 * width, 3 * width, 0),
+            Opcode::DUP(_, _, _) => (width, 2 * width, 0),
+            Opcode::OVR(_, _, _) => (2 * width, 3 * width, 0),
+            Opcode::EQU(_, _, _)
+            | Opcode::NEQ(_, _, _)
+            | Opcode::GTH(_, _, _)
+            | Opcode::LTH(_, _, _) => (2 * width, 1, 0),
+            Opcode::JMP(_, _, _) => (width, 0, 0),
+            Opcode::JCN(_, _, _) => (width + 1, 0, 0),
+            Opcode::JSR(_, _, _) => (width, 0, 2),
+            Opcode::STH(_, _, _) => (width, 0, width),
+            Opcode::LDZ(_, _, _) | Opcode::LDR(_, _, _) | Opcode::DEI(_, _, _) => (1, width, 0),
+            Opcode::STZ(_, _, _) | Opcode::STR(_, _, _) | Opcode::DEO(_, _, _) => (width + 1, 0, 0),
+            Opcode::LDA(_, _, _) => (2, width, 0),
+            Opcode::STA(_, _, _) => (width + 2, 0, 0),
+            Opcode::ADD(_, _, _)
+            | Opcode::SUB(_, _, _)
+            | Opcode::MUL(_, _, _)
+            | Opcode::DIV(_, _, _)
+            | Opcode::AND(_, _, _)
+            | Opcode::ORA(_, _, _)
+            | Opcode::EOR(_, _, _) => (2 * width, width, 0),
+            Opcode::SFT(_, _, _) => (width + 1, width, 0),
+        };
+        StackEffect {
+            takes,
+            gives,
+            other,
+            keep,
+            return_stack,
+        }
+    }
 
     pub fn as_byte(&self) -> u8 {
         match self {
@@ -187,9 +346,39 @@ mod tests {
 
             let byte = opcode.as_byte();
             assert_eq!(byte, $c, "{}: expected 0x{:x} got 0x{:x}", $a, $c, byte);
+
+            assert_eq!(Opcode::from_byte($c), opcode, "0x{:x}", $c);
+            assert_eq!(opcode.to_string(), $a);
         }};
     }
 
+    fn effect(opcode: &str) -> (usize, usize, usize, bool, bool) {
+        let effect = Opcode::from_str(opcode).unwrap().stack_effect();
+        (
+            effect.takes,
+            effect.gives,
+            effect.other,
+            effect.keep,
+            effect.return_stack,
+        )
+    }
+
+    #[test]
+    fn stack_effects_work() {
+        assert_eq!(effect("ADD"), (2, 1, 0, false, false));
+        assert_eq!(effect("ADD2"), (4, 2, 0, false, false));
+        assert_eq!(effect("ADD2k"), (4, 2, 0, true, false));
+        assert_eq!(effect("EQU2"), (4, 1, 0, false, false));
+        assert_eq!(effect("JCN2"), (3, 0, 0, false, false));
+        assert_eq!(effect("JSR2"), (2, 0, 2, false, false));
+        assert_eq!(effect("STH2r"), (2, 0, 2, false, true));
+        assert_eq!(effect("STA2"), (4, 0, 0, false, false));
+        assert_eq!(effect("SFT2"), (3, 2, 0, false, false));
+        assert_eq!(effect("LIT2r"), (0, 2, 0, false, true));
+        assert_eq!(effect("JCI"), (1, 0, 0, false, false));
+        assert_eq!(effect("JSI"), (0, 0, 2, false, false));
+    }
+
     #[test]
     fn it_handles_errors() {
         let result = Opcode::from_str("DOG");
@@ -0,0 +1,316 @@
+use crate::symbols::Symbols;
+use std::collections::HashSet;
+use std::io::Write;
+use std::str::FromStr;
+
+// ROMs are loaded into memory at this address
+const ROM_START: u16 = 0x100;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutputFormat {
+    Rom,
+    IntelHex,
+    C,
+    Rust,
+    Hex,
+    Base64,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rom" => Ok(OutputFormat::Rom),
+            "ihex" => Ok(OutputFormat::IntelHex),
+            "c" => Ok(OutputFormat::C),
+            "rust" => Ok(OutputFormat::Rust),
+            "hex" => Ok(OutputFormat::Hex),
+            "base64" => Ok(OutputFormat::Base64),
+            _ => Err(format!(
+                "unknown format \"{s}\", expected one of rom, ihex, c, rust, hex, base64"
+            )),
+        }
+    }
+}
+
+// Turns a label or file name into an upper-case identifier, e.g. on-reset/loop becomes
+// ON_RESET_LOOP
+fn identifier(name: &str) -> String {
+    let mut identifier: String = name
+        .chars()
+        .map(|chr| {
+            if chr.is_ascii_alphanumeric() {
+                chr.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !identifier.starts_with(|chr: char| chr.is_ascii_alphabetic() || chr == '_') {
+        identifier.insert(0, '_');
+    }
+    identifier
+}
+
+// Symbols inside the ROM as identifiers prefixed with name and their offset into the ROM.
+// Labels outside the ROM and lambdas are left out, and names that end up the same once
+// they're identifiers get a numbered suffix
+fn offsets(symbols: &Symbols, name: &str) -> Vec<(String, u16)> {
+    let mut seen = HashSet::new();
+    let mut offsets = vec![];
+    for symbol in symbols.sorted() {
+        if symbol.address < ROM_START || symbol.name.starts_with('λ') {
+            continue;
+        }
+        let base = format!("{}_{}", name, identifier(&symbol.name));
+        let mut unique = base.clone();
+        let mut count = 1;
+        while !seen.insert(unique.clone()) {
+            count += 1;
+            unique = format!("{base}_{count}");
+        }
+        offsets.push((unique, symbol.address - ROM_START));
+    }
+    offsets
+}
+
+fn byte_rows(bytes: &[u8], per_row: usize) -> Vec<String> {
+    bytes
+        .chunks(per_row)
+        .map(|row| {
+            let row: Vec<String> = row.iter().map(|byte| format!("0x{byte:02x},")).collect();
+            row.join(" ")
+        })
+        .collect()
+}
+
+fn write_intel_hex(bytes: &[u8], writer: &mut dyn Write) -> std::io::Result<()> {
+    for (i, row) in bytes.chunks(16).enumerate() {
+        let address = ROM_START as usize + i * 16;
+        let mut record = vec![row.len() as u8, (address >> 8) as u8, address as u8, 0];
+        record.extend_from_slice(row);
+        let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        record.push(sum.wrapping_neg());
+        writeln!(writer, ":{}", hex::encode_upper(record))?;
+    }
+    writeln!(writer, ":00000001FF")
+}
+
+fn write_c(
+    bytes: &[u8],
+    symbols: &Symbols,
+    name: &str,
+    writer: &mut dyn Write,
+) -> std::io::Result<()> {
+    let name = identifier(name);
+    for (symbol, offset) in offsets(symbols, &name) {
+        writeln!(writer, "#define {symbol} 0x{offset:04x}")?;
+    }
+    writeln!(
+        writer,
+        "static const unsigned int {}_LENGTH = {};",
+        name,
+        bytes.len()
+    )?;
+    // C has no empty arrays, so an empty ROM only gets its length
+    if bytes.is_empty() {
+        return Ok(());
+    }
+    writeln!(writer, "static const unsigned char {name}[] = {{")?;
+    for row in byte_rows(bytes, 12) {
+        writeln!(writer, "    {row}")?;
+    }
+    writeln!(writer, "}};")
+}
+
+fn write_rust(
+    bytes: &[u8],
+    symbols: &Symbols,
+    name: &str,
+    writer: &mut dyn Write,
+) -> std::io::Result<()> {
+    let name = identifier(name);
+    for (symbol, offset) in offsets(symbols, &name) {
+        writeln!(writer, "pub const {symbol}: usize = 0x{offset:04x};")?;
+    }
+    writeln!(writer, "pub const {}: [u8; {}] = [", name, bytes.len())?;
+    for row in byte_rows(bytes, 12) {
+        writeln!(writer, "    {row}")?;
+    }
+    writeln!(writer, "];")
+}
+
+// Like hexdump -C, with addresses as they'll be once the ROM is loaded
+fn write_hex(bytes: &[u8], writer: &mut dyn Write) -> std::io::Result<()> {
+    for (i, row) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = row.iter().map(|byte| format!("{byte:02x}")).collect();
+        let ascii: String = row
+            .iter()
+            .map(|byte| {
+                if byte.is_ascii_graphic() || *byte == b' ' {
+                    *byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let address = ROM_START as usize + i * 16;
+        writeln!(
+            writer,
+            "{:04x}  {:<47}  |{}|",
+            address,
+            hex.join(" "),
+            ascii
+        )?;
+    }
+    Ok(())
+}
+
+fn write_base64(bytes: &[u8], writer: &mut dyn Write) -> std::io::Result<()> {
+    let mut encoded = vec![];
+    for group in bytes.chunks(3) {
+        let mut padded = [0u8; 3];
+        padded[..group.len()].copy_from_slice(group);
+        let value = (padded[0] as u32) << 16 | (padded[1] as u32) << 8 | padded[2] as u32;
+        for i in 0..4 {
+            if i <= group.len() {
+                encoded.push(BASE64_ALPHABET[(value >> (18 - i * 6)) as usize & 0x3f]);
+            } else {
+                encoded.push(b'=');
+            }
+        }
+    }
+    for line in encoded.chunks(76) {
+        writer.write_all(line)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+// Writes the ROM's bytes in format. name is used for the array and offset names in the C and
+// Rust formats
+pub fn write_rom(
+    bytes: &[u8],
+    symbols: &Symbols,
+    format: OutputFormat,
+    name: &str,
+    writer: &mut dyn Write,
+) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Rom => writer.write_all(bytes),
+        OutputFormat::IntelHex => write_intel_hex(bytes, writer),
+        OutputFormat::C => write_c(bytes, symbols, name, writer),
+        OutputFormat::Rust => write_rust(bytes, symbols, name, writer),
+        OutputFormat::Hex => write_hex(bytes, writer),
+        OutputFormat::Base64 => write_base64(bytes, writer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(bytes: &[u8], format: OutputFormat) -> String {
+        let mut symbols = Symbols::new();
+        symbols.push("System".to_string(), 0x0000);
+        symbols.push("on-reset".to_string(), 0x0100);
+        symbols.push("on-reset/loop".to_string(), 0x0102);
+        symbols.push("on-reset-loop".to_string(), 0x0103);
+        symbols.push("λ00".to_string(), 0x0104);
+
+        let mut output = vec![];
+        write_rom(bytes, &symbols, format, "hello-world", &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn it_parses_formats() {
+        assert_eq!(OutputFormat::from_str("ihex"), Ok(OutputFormat::IntelHex));
+        assert_eq!(OutputFormat::from_str("rust"), Ok(OutputFormat::Rust));
+        assert!(OutputFormat::from_str("elf").is_err());
+    }
+
+    #[test]
+    fn it_writes_intel_hex() {
+        let bytes: Vec<u8> = (0..20).collect();
+        let expected = "\
+:10010000000102030405060708090A0B0C0D0E0F77
+:0401100010111213A5
+:00000001FF
+";
+        assert_eq!(write(&bytes, OutputFormat::IntelHex), expected);
+    }
+
+    #[test]
+    fn it_writes_c() {
+        let expected = "\
+#define HELLO_WORLD_ON_RESET 0x0000
+#define HELLO_WORLD_ON_RESET_LOOP 0x0002
+#define HELLO_WORLD_ON_RESET_LOOP_2 0x0003
+static const unsigned int HELLO_WORLD_LENGTH = 5;
+static const unsigned char HELLO_WORLD[] = {
+    0x80, 0x68, 0x80, 0x18, 0x17,
+};
+";
+        assert_eq!(
+            write(&[0x80, 0x68, 0x80, 0x18, 0x17], OutputFormat::C),
+            expected
+        );
+    }
+
+    #[test]
+    fn it_writes_empty_roms_as_c() {
+        let expected = "\
+#define HELLO_WORLD_ON_RESET 0x0000
+#define HELLO_WORLD_ON_RESET_LOOP 0x0002
+#define HELLO_WORLD_ON_RESET_LOOP_2 0x0003
+static const unsigned int HELLO_WORLD_LENGTH = 0;
+";
+        assert_eq!(write(&[], OutputFormat::C), expected);
+    }
+
+    #[test]
+    fn it_writes_rust() {
+        let expected = "\
+pub const HELLO_WORLD_ON_RESET: usize = 0x0000;
+pub const HELLO_WORLD_ON_RESET_LOOP: usize = 0x0002;
+pub const HELLO_WORLD_ON_RESET_LOOP_2: usize = 0x0003;
+pub const HELLO_WORLD: [u8; 5] = [
+    0x80, 0x68, 0x80, 0x18, 0x17,
+];
+";
+        assert_eq!(
+            write(&[0x80, 0x68, 0x80, 0x18, 0x17], OutputFormat::Rust),
+            expected
+        );
+    }
+
+    #[test]
+    fn it_writes_hex() {
+        let expected = "\
+0100  80 68 65 6c 6c 6f 20 77 6f 72 6c 64 21 0a 00 01  |.hello world!...|
+0110  02                                               |.|
+";
+        assert_eq!(
+            write(b"\x80hello world!\n\x00\x01\x02", OutputFormat::Hex),
+            expected
+        );
+    }
+
+    #[test]
+    fn it_writes_base64() {
+        assert_eq!(write(b"uxn", OutputFormat::Base64), "dXhu\n");
+        assert_eq!(write(b"uxntal", OutputFormat::Base64), "dXhudGFs\n");
+        assert_eq!(write(b"tal", OutputFormat::Base64), "dGFs\n");
+        assert_eq!(write(b"ta", OutputFormat::Base64), "dGE=\n");
+        assert_eq!(write(b"t", OutputFormat::Base64), "dA==\n");
+        assert_eq!(
+            write(&[0xff; 60], OutputFormat::Base64),
+            "/".repeat(76) + "\n////\n"
+        );
+    }
+}
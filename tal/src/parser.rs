@@ -1,18 +1,20 @@
 use crate::chunker::Chunk;
-use crate::chunker::Chunker;
 use crate::error::Error;
+use crate::include_resolver::IncludeResolver;
+use crate::listing::Listing;
+use crate::listing::ListingEntry;
 use crate::opcode::Opcode;
 use crate::pre_process_brackets::PreProcessBrackets;
 use crate::pre_process_comments::PreProcessComments;
 use crate::pre_process_includes::PreProcessIncludes;
 use crate::pre_process_macros::PreProcessMacros;
+use crate::symbols::Symbols;
 use crate::token::Token;
 use crate::token::TokenType;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt::Debug;
 use std::fmt::Formatter;
-use std::fs::File;
-use std::io::BufReader;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -31,10 +33,27 @@ enum FillLater {
     Short(u16, bool, String, Chunk),
 }
 
-#[derive(PartialEq)]
+impl FillLater {
+    fn name(&self) -> &str {
+        match self {
+            FillLater::Byte(_, _, name, _) => name,
+            FillLater::Short(_, _, name, _) => name,
+        }
+    }
+}
+
 pub struct Rom {
     rom: [u8; 0xff00],
     highest_byte_written: usize,
+    // How many zeros have been trimmed from the end of what was written
+    trimmed: usize,
+}
+
+// ROMs are the same if they'd be written out the same
+impl PartialEq for Rom {
+    fn eq(&self, other: &Self) -> bool {
+        self.get_bytes() == other.get_bytes()
+    }
 }
 
 impl Debug for Rom {
@@ -53,28 +72,75 @@ impl Debug for Rom {
     }
 }
 
+impl Default for Rom {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Rom {
     pub fn new() -> Self {
         Rom {
             rom: [0; 0xff00],
             highest_byte_written: 0,
+            trimmed: 0,
         }
     }
 
-    pub fn write_byte(&mut self, position: u16, byte: u8) {
+    pub fn write_byte(&mut self, position: u16, byte: u8) -> Result<(), String> {
         if position < 0x100 {
-            panic!("Cannot write at i < 0x100 where i={:x}", position);
+            return Err(format!(
+                "cannot write to the zero page at 0x{:04x}, code and data must start at 0x0100",
+                position
+            ));
         }
         let real_position = position as usize - 0x100;
         if real_position > self.highest_byte_written {
             self.highest_byte_written = real_position;
         }
         self.rom[real_position] = byte;
+        Ok(())
     }
 
     pub fn get_bytes(&self) -> &[u8] {
+        let written = self.get_written_bytes();
+        &written[..written.len() - self.trimmed]
+    }
+
+    // Everything written, including any zeros that were trimmed
+    pub fn get_written_bytes(&self) -> &[u8] {
         &self.rom[0..self.highest_byte_written + 1]
     }
+
+    // Leaves trailing zeros out of the ROM like uxnasm does, they're what memory starts as
+    // anyway. Returns how many were left out
+    pub fn trim(&mut self) -> usize {
+        let written = self.get_written_bytes();
+        let length = match written.iter().rposition(|byte| *byte != 0) {
+            Some(last) => last + 1,
+            None => 0,
+        };
+        self.trimmed = written.len() - length;
+        self.trimmed
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ParseOptions {
+    // Warn about labels that are defined but never referenced
+    pub warn_unused: bool,
+    // Keep trailing zeros in the ROM
+    pub no_trim: bool,
+}
+
+#[derive(Debug)]
+pub struct Assembly {
+    pub rom: Rom,
+    pub symbols: Symbols,
+    pub warnings: Vec<Error>,
+    pub listing: Listing,
+    // How many trailing zeros were left out of the ROM
+    pub trimmed: usize,
 }
 
 fn get_full_name(name: String, parent: &Option<String>, child: bool) -> String {
@@ -90,11 +156,99 @@ fn get_full_name(name: String, parent: &Option<String>, child: bool) -> String {
     }
 }
 
+// Stop collecting errors after this many, later ones are likely to be knock-on effects. One more
+// than this is collected, so that more than MAX_ERRORS errors means some were left out
+pub const MAX_ERRORS: usize = 20;
+
+// Records an error, returning true once there are too many to carry on
+fn push_error(errors: &mut Vec<Error>, error: Error) -> bool {
+    errors.push(error);
+    errors.len() > MAX_ERRORS
+}
+
+// An error at chunk, in the file chunk was read from, which is file unless it was included
+fn error_at(message: String, chunk: Chunk, file: &Path) -> Error {
+    let file = chunk.file(file).to_path_buf();
+    Error::new(message, chunk, file)
+}
+
+fn duplicate_label_error(name: &str, first: &Chunk, chunk: Chunk, file: &Path) -> Error {
+    error_at(format!("label \"{name}\" is already defined"), chunk, file)
+        .with_code("E0011")
+        .with_label(
+            "first defined here".to_string(),
+            first.clone(),
+            first.file(file).to_path_buf(),
+        )
+}
+
+// Labels starting with a capital letter conventionally describe devices and structs, and the
+// reset vector is the entry point, so neither is expected to be referenced
+fn should_warn_unused(name: &str, address: u16, referenced: &HashSet<&str>) -> bool {
+    if referenced.contains(name) || address == 0x100 || name.starts_with('λ') {
+        return false;
+    }
+    if name.starts_with(|chr: char| chr.is_ascii_uppercase()) {
+        return false;
+    }
+    // A parent is in use if any of its children are
+    let prefix = format!("{name}/");
+    !referenced.iter().any(|other| other.starts_with(&prefix))
+}
+
+// Relative addresses are measured from 2 bytes past the address being filled in
+fn relative_distance(source: u16, target: u16) -> i32 {
+    source as i32 - (target as i32 + 2)
+}
+
+fn out_of_range_error(name: &str, distance: i32, chunk: Chunk, file: &Path) -> Error {
+    error_at(
+        format!("relative reference to \"{name}\" is too far away, distance {distance} does not fit in a signed byte"),
+        chunk,
+        file,
+    )
+    .with_code("E0013")
+    .with_help(format!("use an absolute reference such as ;{name} instead"))
+}
+
+fn too_large_error(chunk: Chunk, file: &Path) -> Error {
+    error_at(
+        "ROM too large, it does not fit in 64KB".to_string(),
+        chunk,
+        file,
+    )
+    .with_code("E0016")
+}
+
+fn unknown_name_error(name: &str, chunk: Chunk, file: &Path) -> Error {
+    error_at(format!("unknown name \"{name}\""), chunk, file).with_code("E0012")
+}
+
+fn write_error(message: String, chunk: Chunk, file: &Path) -> Error {
+    error_at(message, chunk, file)
+        .with_code("E0015")
+        .with_help("add |0100 before the first instruction or data".to_string())
+}
+
+// Writes bytes starting at position, which must already be known to fit before 0x10000
+fn write_bytes(rom: &mut Rom, position: usize, bytes: &[u8]) -> Result<(), String> {
+    for (i, byte) in bytes.iter().enumerate() {
+        rom.write_byte((position + i) as u16, *byte)?;
+    }
+    Ok(())
+}
+
+fn lambda_name(id: usize) -> String {
+    format!("λ{id:02x}")
+}
+
 fn parse(
     file: PathBuf,
+    options: &ParseOptions,
     chunks: &mut dyn Iterator<Item = Result<Chunk, Error>>,
-) -> Result<Rom, Error> {
-    let mut position: u16 = 0x100;
+) -> Result<Assembly, Vec<Error>> {
+    let mut position: usize = 0x100;
+    let mut errors: Vec<Error> = vec![];
 
     // Addresses, references, etc
 
@@ -102,137 +256,303 @@ fn parse(
     let mut parent: Option<String> = None;
     // Map of names to the addresses they refer to
     let mut address_references: HashMap<String, u16> = HashMap::new();
+    // Map of names to the chunks that defined them
+    let mut label_definitions: HashMap<String, Chunk> = HashMap::new();
     // Map of addresses to the names of references that should be filled in
     let mut fill_later: Vec<FillLater> = vec![];
+    // Every label in the order it was defined
+    let mut symbols = Symbols::new();
+    // Every token and where it ended up
+    let mut listing = Listing::new();
+    // Lambdas that have been opened but not yet closed, and how many have been opened in total
+    let mut lambda_stack: Vec<(usize, Chunk)> = vec![];
+    let mut lambda_count: usize = 0;
+    // Padding to labels that weren't defined yet, which left the position where it was
+    let mut unresolved_padding: Vec<(String, Chunk)> = vec![];
+    // Labels padded to, which count as references to them
+    let mut padding_references: Vec<String> = vec![];
 
     let mut rom = Rom::new();
 
     for chunk in chunks {
-        let chunk = chunk?;
-        match Token::from_chunk(&chunk) {
-            Err(err) => return Err(Error::new(err, chunk, file)),
-            Ok(token) => match token.token_type {
-                TokenType::Opcode(opcode) => {
-                    rom.write_byte(position, opcode.as_byte());
-                    position += 1;
-                }
-                TokenType::RawByte(byte) => {
-                    rom.write_byte(position, byte);
-                    position += 1;
-                }
-                TokenType::RawShort(short) => {
-                    let (high, low) = split_short(short);
-                    rom.write_byte(position, high);
-                    position += 1;
-                    rom.write_byte(position, low);
-                    position += 1;
-                }
-                TokenType::PaddingAbsolute(offset) => {
-                    position = offset;
-                }
-                TokenType::PaddingRelative(offset) => {
-                    position += offset;
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                if push_error(&mut errors, err) {
+                    return Err(errors);
                 }
-                TokenType::RawAscii(value) => {
-                    for byte in value.bytes() {
-                        rom.write_byte(position, byte);
-                        position += 1;
-                    }
+                continue;
+            }
+        };
+        let token = match Token::from_chunk(&chunk) {
+            Ok(token) => token,
+            Err(err) => {
+                // Skip the token and carry on, so that later errors are reported too
+                let error = error_at(err, chunk, &file).with_code("E0009");
+                if push_error(&mut errors, error) {
+                    return Err(errors);
                 }
-                TokenType::LiteralByte(byte) => {
-                    rom.write_byte(position, Opcode::LIT(false, false).as_byte());
-                    position += 1;
-                    rom.write_byte(position, byte);
-                    position += 1;
+                continue;
+            }
+        };
+
+        // The bytes this token writes at the current position, a reference within them to fill
+        // in later, and the name of a label it defines at the current position
+        let mut bytes: Vec<u8> = vec![];
+        let mut fill: Option<FillLater> = None;
+        let mut label: Option<String> = None;
+
+        // Addresses in fill are truncated to u16, but they're only used once the bytes they
+        // point into have been written, at which point they're known to fit
+        match token.token_type {
+            TokenType::Opcode(opcode) => {
+                bytes.push(opcode.as_byte());
+            }
+            TokenType::RawByte(byte) => {
+                bytes.push(byte);
+            }
+            TokenType::RawShort(short) => {
+                let (high, low) = split_short(short);
+                bytes.extend([high, low]);
+            }
+            TokenType::PaddingAbsolute(offset) => {
+                position = offset as usize;
+            }
+            TokenType::PaddingRelative(offset) => {
+                position += offset as usize;
+            }
+            TokenType::PaddingAbsoluteLabel(name, child) => {
+                let full_name = get_full_name(name, &parent, child);
+                padding_references.push(full_name.clone());
+                match address_references.get(&full_name) {
+                    Some(address) => position = *address as usize,
+                    None => unresolved_padding.push((full_name, chunk.clone())),
                 }
-                TokenType::LiteralShort(short) => {
-                    rom.write_byte(position, Opcode::LIT(true, false).as_byte());
-                    position += 1;
-
-                    let (high, low) = split_short(short);
-                    rom.write_byte(position, high);
-                    position += 1;
-                    rom.write_byte(position, low);
-                    position += 1;
+            }
+            TokenType::PaddingRelativeLabel(name, child) => {
+                let full_name = get_full_name(name, &parent, child);
+                padding_references.push(full_name.clone());
+                match address_references.get(&full_name) {
+                    Some(address) => position += *address as usize,
+                    None => unresolved_padding.push((full_name, chunk.clone())),
                 }
-                TokenType::AddressLiteralAbsoluteByte(name, child) => {
-                    let full_name = get_full_name(name, &parent, child);
+            }
+            TokenType::RawAscii(value) => {
+                bytes.extend(value.bytes());
+            }
+            TokenType::LiteralByte(byte) => {
+                bytes.extend([Opcode::LIT(false, false).as_byte(), byte]);
+            }
+            TokenType::LiteralShort(short) => {
+                let (high, low) = split_short(short);
+                bytes.extend([Opcode::LIT(true, false).as_byte(), high, low]);
+            }
+            TokenType::AddressLiteralAbsoluteByte(name, child) => {
+                let full_name = get_full_name(name, &parent, child);
 
-                    rom.write_byte(position, Opcode::LIT(false, false).as_byte());
-                    position += 1;
+                bytes.extend([Opcode::LIT(false, false).as_byte(), 0]);
+                let target = (position + 1) as u16;
+                fill = Some(FillLater::Byte(target, false, full_name, chunk.clone()));
+            }
+            TokenType::AddressLiteralAbsoluteShort(name, child) => {
+                let full_name = get_full_name(name, &parent, child);
 
-                    fill_later.push(FillLater::Byte(position, false, full_name, chunk));
-                    position += 1;
-                }
-                TokenType::AddressLiteralAbsoluteShort(name, child) => {
-                    let full_name = get_full_name(name, &parent, child);
+                bytes.extend([Opcode::LIT(true, false).as_byte(), 0, 0]);
+                let target = (position + 1) as u16;
+                fill = Some(FillLater::Short(target, false, full_name, chunk.clone()));
+            }
+            TokenType::AddressLiteralRelative(name, child) => {
+                let full_name = get_full_name(name, &parent, child);
 
-                    rom.write_byte(position, Opcode::LIT(true, false).as_byte());
-                    position += 1;
+                bytes.extend([Opcode::LIT(false, false).as_byte(), 0]);
+                let target = (position + 1) as u16;
+                fill = Some(FillLater::Byte(target, true, full_name, chunk.clone()));
+            }
+            TokenType::AddressRawAbsoluteByte(name, child) => {
+                let full_name = get_full_name(name, &parent, child);
 
-                    fill_later.push(FillLater::Short(position, false, full_name, chunk));
-                    position += 2;
-                }
-                TokenType::AddressLiteralRelative(name, child) => {
-                    let full_name = get_full_name(name, &parent, child);
+                bytes.push(0);
+                let target = position as u16;
+                fill = Some(FillLater::Byte(target, false, full_name, chunk.clone()));
+            }
+            TokenType::AddressRawAbsoluteShort(name, child) => {
+                let full_name = get_full_name(name, &parent, child);
 
-                    rom.write_byte(position, Opcode::LIT(false, false).as_byte());
-                    position += 1;
+                bytes.extend([0, 0]);
+                let target = position as u16;
+                fill = Some(FillLater::Short(target, false, full_name, chunk.clone()));
+            }
+            TokenType::AddressRawRelative(name, child) => {
+                let full_name = get_full_name(name, &parent, child);
 
-                    fill_later.push(FillLater::Byte(position, true, full_name, chunk));
-                    position += 1;
-                }
-                TokenType::AddressRawAbsoluteByte(name, child) => {
-                    let full_name = get_full_name(name, &parent, child);
+                bytes.push(0);
+                let target = position as u16;
+                fill = Some(FillLater::Byte(target, true, full_name, chunk.clone()));
+            }
+            TokenType::ImmediateUnconditional(name, child) => {
+                let full_name = get_full_name(name, &parent, child);
 
-                    fill_later.push(FillLater::Byte(position, false, full_name, chunk));
-                    position += 1;
-                }
-                TokenType::AddressRawAbsoluteShort(name, child) => {
-                    let full_name = get_full_name(name, &parent, child);
+                bytes.extend([Opcode::JMI.as_byte(), 0, 0]);
+                let target = (position + 1) as u16;
+                fill = Some(FillLater::Short(target, true, full_name, chunk.clone()));
+            }
+            TokenType::ImmediateConditional(name, child) => {
+                let full_name = get_full_name(name, &parent, child);
 
-                    fill_later.push(FillLater::Short(position, false, full_name, chunk));
-                    position += 2;
-                }
-                TokenType::AddressRawRelative(name, child) => {
-                    let full_name = get_full_name(name, &parent, child);
+                bytes.extend([Opcode::JCI.as_byte(), 0, 0]);
+                let target = (position + 1) as u16;
+                fill = Some(FillLater::Short(target, true, full_name, chunk.clone()));
+            }
+            TokenType::LabelParent(name) => {
+                parent = Some(name.clone());
+                label = Some(name);
+            }
+            TokenType::LabelChild(name) => {
+                label = Some(get_full_name(name, &parent, true));
+            }
+            TokenType::Instant(name) => {
+                bytes.extend([Opcode::JSI.as_byte(), 0, 0]);
+                let target = (position + 1) as u16;
+                fill = Some(FillLater::Short(target, true, name, chunk.clone()));
+            }
+            TokenType::LambdaInstant
+            | TokenType::LambdaConditional
+            | TokenType::LambdaUnconditional => {
+                let opcode = match token.token_type {
+                    TokenType::LambdaConditional => Opcode::JCI,
+                    TokenType::LambdaUnconditional => Opcode::JMI,
+                    _ => Opcode::JSI,
+                };
 
-                    fill_later.push(FillLater::Byte(position, true, full_name, chunk));
-                    position += 1;
+                // Jump past the body, the matching } defines the label
+                let name = lambda_name(lambda_count);
+                lambda_stack.push((lambda_count, chunk.clone()));
+                lambda_count += 1;
+
+                bytes.extend([opcode.as_byte(), 0, 0]);
+                let target = (position + 1) as u16;
+                fill = Some(FillLater::Short(target, true, name, chunk.clone()));
+            }
+            TokenType::LambdaEnd => match lambda_stack.pop() {
+                None => {
+                    let error = error_at("found } without matching {".to_string(), chunk, &file)
+                        .with_code("E0010");
+                    if push_error(&mut errors, error) {
+                        return Err(errors);
+                    }
+                    continue;
                 }
-                TokenType::ImmediateUnconditional(name, child) => {
-                    let full_name = get_full_name(name, &parent, child);
+                Some((id, _)) => {
+                    label = Some(lambda_name(id));
+                }
+            },
+        }
 
-                    rom.write_byte(position, Opcode::JMI.as_byte());
-                    position += 1;
+        // Nothing after this point could be placed either, so stop here
+        if position + bytes.len() > 0x10000 {
+            errors.push(too_large_error(chunk, &file));
+            return Err(errors);
+        }
 
-                    fill_later.push(FillLater::Short(position, true, full_name, chunk));
-                    position += 2;
+        if let Some(name) = label {
+            if let Some(first) = label_definitions.get(&name) {
+                let error = duplicate_label_error(&name, first, chunk, &file);
+                if push_error(&mut errors, error) {
+                    return Err(errors);
                 }
-                TokenType::ImmediateConditional(name, child) => {
-                    let full_name = get_full_name(name, &parent, child);
+            } else if position > 0xffff {
+                errors.push(too_large_error(chunk, &file));
+                return Err(errors);
+            } else {
+                symbols.push(name.clone(), position as u16);
+                address_references.insert(name.clone(), position as u16);
+                listing.push(ListingEntry {
+                    address: position as u16,
+                    length: 0,
+                    label: Some(name.clone()),
+                    parent: parent.clone(),
+                    chunk: chunk.clone(),
+                });
+                label_definitions.insert(name, chunk);
+            }
+            continue;
+        }
 
-                    rom.write_byte(position, Opcode::JCI.as_byte());
-                    position += 1;
+        // Padding can leave the position just past the end of memory, where nothing is listed
+        if position <= 0xffff {
+            listing.push(ListingEntry {
+                address: position as u16,
+                length: bytes.len() as u16,
+                label: None,
+                parent: parent.clone(),
+                chunk: chunk.clone(),
+            });
+        }
 
-                    fill_later.push(FillLater::Short(position, true, full_name, chunk));
-                    position += 2;
-                }
-                TokenType::LabelParent(name) => {
-                    parent = Some(name.clone());
-                    address_references.insert(name, position);
+        let written = write_bytes(&mut rom, position, &bytes);
+        position += bytes.len();
+        match written {
+            Ok(_) => fill_later.extend(fill),
+            Err(err) => {
+                if push_error(&mut errors, write_error(err, chunk, &file)) {
+                    return Err(errors);
                 }
-                TokenType::LabelChild(name) => {
-                    let full_name = get_full_name(name, &parent, true);
-                    address_references.insert(full_name, position);
-                }
-                TokenType::Instant(name) => {
-                    rom.write_byte(position, Opcode::JSI.as_byte());
-                    position += 1;
-                    fill_later.push(FillLater::Short(position, true, name, chunk));
-                    position += 2;
-                }
-            },
+            }
+        }
+    }
+
+    // Positions depend on padding, so it can only use labels that are already placed
+    for (name, chunk) in unresolved_padding {
+        let error = match label_definitions.get(&name) {
+            Some(definition) => {
+                let definition_file = definition.file(&file).to_path_buf();
+                error_at(
+                    format!("cannot pad to \"{name}\" before it is defined"),
+                    chunk,
+                    &file,
+                )
+                .with_code("E0017")
+                .with_label(
+                    "defined here".to_string(),
+                    definition.clone(),
+                    definition_file,
+                )
+                .with_help("move the padding after the label, or pad to a number".to_string())
+            }
+            None => unknown_name_error(&name, chunk, &file),
+        };
+        if push_error(&mut errors, error) {
+            return Err(errors);
+        }
+    }
+
+    for (_, chunk) in lambda_stack {
+        let error = error_at(
+            "reached EOF without finding lambda close".to_string(),
+            chunk,
+            &file,
+        )
+        .with_code("E0010");
+        if push_error(&mut errors, error) {
+            return Err(errors);
+        }
+    }
+
+    let mut warnings = vec![];
+    if options.warn_unused {
+        let filled = fill_later.iter().map(|fill| fill.name());
+        let padded = padding_references.iter().map(String::as_str);
+        let referenced: HashSet<&str> = filled.chain(padded).collect();
+        for symbol in symbols.iter() {
+            if should_warn_unused(&symbol.name, symbol.address, &referenced) {
+                let chunk = label_definitions.get(&symbol.name).unwrap().clone();
+                let message = format!("label \"{}\" is never used", symbol.name);
+                let chunk_file = chunk.file(&file).to_path_buf();
+                let warning = Error::warning(message, chunk, chunk_file)
+                    .with_code("W0001")
+                    .with_help("remove the label, or reference it".to_string());
+                warnings.push(warning);
+            }
         }
     }
 
@@ -242,193 +562,263 @@ fn parse(
             FillLater::Byte(target, relative, name, chunk) => {
                 let source = address_references.get(&name);
                 if source.is_none() {
-                    return Err(Error::new(format!("unknown name \"{name}\""), chunk, file));
+                    let error = unknown_name_error(&name, chunk, &file);
+                    if push_error(&mut errors, error) {
+                        return Err(errors);
+                    }
+                    continue;
                 }
                 let mut source = *source.unwrap();
                 if relative {
-                    // Unclear what the 2 is for, but it makes it work
-                    source = source.wrapping_sub(target + 2);
+                    let distance = relative_distance(source, target);
+                    if i8::try_from(distance).is_err() {
+                        let error = out_of_range_error(&name, distance, chunk, &file);
+                        if push_error(&mut errors, error) {
+                            return Err(errors);
+                        }
+                        continue;
+                    }
+                    source = distance as u16;
+                } else if source > 0xff {
+                    let message = format!(
+                        "zero-page reference to \"{name}\" resolves to 0x{source:04x}, which is outside the zero page"
+                    );
+                    let definition = label_definitions.get(&name).unwrap().clone();
+                    let definition_file = definition.file(&file).to_path_buf();
+                    let error = error_at(message, chunk, &file)
+                        .with_code("E0014")
+                        .with_label(
+                            format!("\"{name}\" is defined here"),
+                            definition,
+                            definition_file,
+                        )
+                        .with_help(format!("use an absolute reference such as ;{name} instead"));
+                    if push_error(&mut errors, error) {
+                        return Err(errors);
+                    }
+                    continue;
                 }
                 let (_high, low) = split_short(source);
-                rom.write_byte(target, low);
+                if let Err(err) = write_bytes(&mut rom, target as usize, &[low]) {
+                    if push_error(&mut errors, write_error(err, chunk, &file)) {
+                        return Err(errors);
+                    }
+                }
             }
             FillLater::Short(target, relative, name, chunk) => {
                 let source = address_references.get(&name);
                 if source.is_none() {
-                    return Err(Error::new(format!("unknown name \"{name}\""), chunk, file));
+                    let error = unknown_name_error(&name, chunk, &file);
+                    if push_error(&mut errors, error) {
+                        return Err(errors);
+                    }
+                    continue;
                 }
                 let mut source = *source.unwrap();
+                // The program counter wraps, so a short distance reaches any address
                 if relative {
-                    // Unclear what the 2 is for, but it makes it work
-                    source = source.wrapping_sub(target + 2);
+                    source = relative_distance(source, target) as u16;
                 }
                 let (high, low) = split_short(source);
-                rom.write_byte(target, high);
-                rom.write_byte(target + 1, low);
+                if let Err(err) = write_bytes(&mut rom, target as usize, &[high, low]) {
+                    if push_error(&mut errors, write_error(err, chunk, &file)) {
+                        return Err(errors);
+                    }
+                }
             }
         }
     }
 
-    Ok(rom)
-}
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let trimmed = if options.no_trim { 0 } else { rom.trim() };
 
-pub fn chunk_file(cwd: &Path, file: &Path) -> Vec<Result<Chunk, Error>> {
-    let full_path = cwd.join(file);
-    let mut input = BufReader::new(File::open(full_path).unwrap());
-    let mut chunker = Chunker::new(&mut input);
-    pre_process(cwd, file.to_path_buf(), &mut chunker)
+    Ok(Assembly {
+        rom,
+        symbols,
+        warnings,
+        listing,
+        trimmed,
+    })
 }
 
 pub fn parse_chunks(
-    cwd: &Path,
+    resolver: &dyn IncludeResolver,
     file: PathBuf,
+    options: &ParseOptions,
     input: &mut dyn Iterator<Item = Result<Chunk, Error>>,
-) -> Result<Rom, Error> {
+) -> Result<Assembly, Vec<Error>> {
     parse(
         file.clone(),
-        &mut pre_process(cwd, file.clone(), input).into_iter(),
+        options,
+        &mut pre_process(resolver, file.clone(), vec![], input).into_iter(),
     )
 }
 
 pub fn pre_process(
-    cwd: &Path,
+    resolver: &dyn IncludeResolver,
     file: PathBuf,
+    includes: Vec<(PathBuf, Chunk)>,
     input: &mut dyn Iterator<Item = Result<Chunk, Error>>,
 ) -> Vec<Result<Chunk, Error>> {
     let mut pp = input;
     let mut pp = PreProcessBrackets::new(&mut pp);
     let mut pp = PreProcessComments::new(file.clone(), &mut pp);
-    let mut pp = PreProcessIncludes::new(cwd, &mut pp);
-    let pp = PreProcessMacros::new(&mut pp);
+    let mut pp = PreProcessIncludes::new(resolver, file.clone(), includes, &mut pp);
+    let pp = PreProcessMacros::new(file.clone(), &mut pp);
     pp.collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chunker::Chunker;
+    use crate::include_resolver::FileSystemResolver;
     use std::io::Cursor;
 
     #[test]
     fn it_works() {
         let mut expected = Rom::new();
-        expected.write_byte(0x100, 0x80);
-        expected.write_byte(0x101, 0x68);
-        expected.write_byte(0x102, 0x80);
-        expected.write_byte(0x103, 0x18);
-        expected.write_byte(0x104, 0x17);
-        expected.write_byte(0x105, 0x80);
-        expected.write_byte(0x106, 0x00);
-        expected.write_byte(0x107, 0x37);
-        expected.write_byte(0x108, 0x80);
-        expected.write_byte(0x109, 0x00);
-        expected.write_byte(0x10a, 0x37);
+        expected.write_byte(0x100, 0x80).unwrap();
+        expected.write_byte(0x101, 0x68).unwrap();
+        expected.write_byte(0x102, 0x80).unwrap();
+        expected.write_byte(0x103, 0x18).unwrap();
+        expected.write_byte(0x104, 0x17).unwrap();
+        expected.write_byte(0x105, 0x80).unwrap();
+        expected.write_byte(0x106, 0x00).unwrap();
+        expected.write_byte(0x107, 0x37).unwrap();
+        expected.write_byte(0x108, 0x80).unwrap();
+        expected.write_byte(0x109, 0x00).unwrap();
+        expected.write_byte(0x10a, 0x37).unwrap();
 
         let mut chunks = vec![
-            Ok(Chunk::new(String::from("|00"), 0, 0)),
-            Ok(Chunk::new(String::from("@System"), 0, 4)),
-            Ok(Chunk::new(String::from("&vector"), 0, 12)),
-            Ok(Chunk::new(String::from("|0100"), 1, 0)),
-            Ok(Chunk::new(String::from("LIT"), 1, 7)),
-            Ok(Chunk::new(String::from("68"), 1, 11)),
-            Ok(Chunk::new(String::from("LIT"), 1, 14)),
-            Ok(Chunk::new(String::from("18"), 1, 18)),
-            Ok(Chunk::new(String::from("DEO"), 1, 21)),
-            Ok(Chunk::new(String::from(".System/vector"), 2, 0)),
-            Ok(Chunk::new(String::from("DEO2"), 2, 15)),
-            Ok(Chunk::new(String::from(".&vector"), 3, 0)),
-            Ok(Chunk::new(String::from("DEO2"), 3, 15)),
+            Ok(Chunk::new(String::from("|00"), 0, 0, 0)),
+            Ok(Chunk::new(String::from("@System"), 0, 4, 4)),
+            Ok(Chunk::new(String::from("&vector"), 0, 12, 12)),
+            Ok(Chunk::new(String::from("|0100"), 1, 0, 20)),
+            Ok(Chunk::new(String::from("LIT"), 1, 7, 27)),
+            Ok(Chunk::new(String::from("68"), 1, 11, 31)),
+            Ok(Chunk::new(String::from("LIT"), 1, 14, 34)),
+            Ok(Chunk::new(String::from("18"), 1, 18, 38)),
+            Ok(Chunk::new(String::from("DEO"), 1, 21, 41)),
+            Ok(Chunk::new(String::from(".System/vector"), 2, 0, 45)),
+            Ok(Chunk::new(String::from("DEO2"), 2, 15, 60)),
+            Ok(Chunk::new(String::from(".&vector"), 3, 0, 65)),
+            Ok(Chunk::new(String::from("DEO2"), 3, 15, 80)),
         ]
         .into_iter();
-        let result = parse_chunks(Path::new(""), PathBuf::new(), &mut chunks);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
         assert!(result.is_ok());
-        let rom = result.unwrap();
+        let rom = result.unwrap().rom;
         assert_eq!(rom, expected);
     }
 
     #[test]
     fn forward_references_work() {
         let mut expected = Rom::new();
-        expected.write_byte(0x100, 0x80);
-        expected.write_byte(0x101, 0x02);
-        expected.write_byte(0x102, 0xa0);
-        expected.write_byte(0x103, 0x12);
-        expected.write_byte(0x104, 0x34);
-        expected.write_byte(0x105, 0xa0);
-        expected.write_byte(0x106, 0x56);
-        expected.write_byte(0x107, 0x78);
+        expected.write_byte(0x100, 0x80).unwrap();
+        expected.write_byte(0x101, 0x02).unwrap();
+        expected.write_byte(0x102, 0xa0).unwrap();
+        expected.write_byte(0x103, 0x12).unwrap();
+        expected.write_byte(0x104, 0x34).unwrap();
+        expected.write_byte(0x105, 0xa0).unwrap();
+        expected.write_byte(0x106, 0x56).unwrap();
+        expected.write_byte(0x107, 0x78).unwrap();
 
         let mut chunks = vec![
-            Ok(Chunk::new(String::from("|0100"), 0, 0)),
-            Ok(Chunk::new(String::from(",foo"), 0, 6)),
-            Ok(Chunk::new(String::from("#1234"), 0, 11)),
-            Ok(Chunk::new(String::from("@foo"), 0, 17)),
-            Ok(Chunk::new(String::from("#5678"), 0, 23)),
+            Ok(Chunk::new(String::from("|0100"), 0, 0, 0)),
+            Ok(Chunk::new(String::from(",foo"), 0, 6, 6)),
+            Ok(Chunk::new(String::from("#1234"), 0, 11, 11)),
+            Ok(Chunk::new(String::from("@foo"), 0, 17, 17)),
+            Ok(Chunk::new(String::from("#5678"), 0, 23, 23)),
         ]
         .into_iter();
-        let result = parse_chunks(Path::new(""), PathBuf::new(), &mut chunks);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
         assert!(result.is_ok());
-        let rom = result.unwrap();
+        let rom = result.unwrap().rom;
         assert_eq!(rom, expected);
     }
 
     #[test]
     fn backward_references_work() {
         let mut expected = Rom::new();
-        expected.write_byte(0x100, 0xa0);
-        expected.write_byte(0x101, 0x12);
-        expected.write_byte(0x102, 0x34);
-        expected.write_byte(0x103, 0x80);
-        expected.write_byte(0x104, 0xfa);
-        expected.write_byte(0x105, 0xa0);
-        expected.write_byte(0x106, 0x56);
-        expected.write_byte(0x107, 0x78);
+        expected.write_byte(0x100, 0xa0).unwrap();
+        expected.write_byte(0x101, 0x12).unwrap();
+        expected.write_byte(0x102, 0x34).unwrap();
+        expected.write_byte(0x103, 0x80).unwrap();
+        expected.write_byte(0x104, 0xfa).unwrap();
+        expected.write_byte(0x105, 0xa0).unwrap();
+        expected.write_byte(0x106, 0x56).unwrap();
+        expected.write_byte(0x107, 0x78).unwrap();
 
         let mut chunks = vec![
-            Ok(Chunk::new(String::from("|0100"), 0, 0)),
-            Ok(Chunk::new(String::from("@bar"), 0, 6)),
-            Ok(Chunk::new(String::from("#1234"), 0, 11)),
-            Ok(Chunk::new(String::from(",bar"), 0, 17)),
-            Ok(Chunk::new(String::from("#5678"), 0, 23)),
+            Ok(Chunk::new(String::from("|0100"), 0, 0, 0)),
+            Ok(Chunk::new(String::from("@bar"), 0, 6, 6)),
+            Ok(Chunk::new(String::from("#1234"), 0, 11, 11)),
+            Ok(Chunk::new(String::from(",bar"), 0, 17, 17)),
+            Ok(Chunk::new(String::from("#5678"), 0, 23, 23)),
         ]
         .into_iter();
-        let result = parse_chunks(Path::new(""), PathBuf::new(), &mut chunks);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
         assert!(result.is_ok());
-        let rom = result.unwrap();
+        let rom = result.unwrap().rom;
         assert_eq!(rom, expected);
     }
 
     #[test]
     fn comments_work() {
         let mut expected = Rom::new();
-        expected.write_byte(0x100, 0xa0);
-        expected.write_byte(0x101, 0x12);
-        expected.write_byte(0x102, 0x34);
+        expected.write_byte(0x100, 0xa0).unwrap();
+        expected.write_byte(0x101, 0x12).unwrap();
+        expected.write_byte(0x102, 0x34).unwrap();
 
         let mut chunks = vec![
-            Ok(Chunk::new(String::from("|0100"), 0, 0)),
-            Ok(Chunk::new(String::from("("), 0, 6)),
-            Ok(Chunk::new(String::from("#"), 0, 8)),
-            Ok(Chunk::new(String::from(")"), 0, 10)),
-            Ok(Chunk::new(String::from("#1234"), 0, 12)),
+            Ok(Chunk::new(String::from("|0100"), 0, 0, 0)),
+            Ok(Chunk::new(String::from("("), 0, 6, 6)),
+            Ok(Chunk::new(String::from("#"), 0, 8, 8)),
+            Ok(Chunk::new(String::from(")"), 0, 10, 10)),
+            Ok(Chunk::new(String::from("#1234"), 0, 12, 12)),
         ]
         .into_iter();
-        let result = parse_chunks(Path::new(""), PathBuf::new(), &mut chunks);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
         assert!(result.is_ok());
-        let rom = result.unwrap();
+        let rom = result.unwrap().rom;
         assert_eq!(rom, expected);
     }
 
     #[test]
     fn rom() {
         let mut rom = Rom::new();
-        rom.write_byte(0x100, 0x80);
-        rom.write_byte(0x101, 0x68);
-        rom.write_byte(0x102, 0x80);
-        rom.write_byte(0x103, 0x18);
+        rom.write_byte(0x100, 0x80).unwrap();
+        rom.write_byte(0x101, 0x68).unwrap();
+        rom.write_byte(0x102, 0x80).unwrap();
+        rom.write_byte(0x103, 0x18).unwrap();
         // skip one
-        rom.write_byte(0x105, 0x17);
+        rom.write_byte(0x105, 0x17).unwrap();
         // write a null
-        rom.write_byte(0x106, 0x00);
+        rom.write_byte(0x106, 0x00).unwrap();
         let expected: [u8; 7] = [0x80, 0x68, 0x80, 0x18, 0x00, 0x17, 0x00];
 
         assert_eq!(rom.get_bytes(), expected);
@@ -437,12 +827,12 @@ mod tests {
     #[test]
     fn rom_debug() {
         let mut rom = Rom::new();
-        rom.write_byte(0x100, 0x80);
-        rom.write_byte(0x101, 0x68);
-        rom.write_byte(0x102, 0x80);
-        rom.write_byte(0x103, 0x18);
+        rom.write_byte(0x100, 0x80).unwrap();
+        rom.write_byte(0x101, 0x68).unwrap();
+        rom.write_byte(0x102, 0x80).unwrap();
+        rom.write_byte(0x103, 0x18).unwrap();
         // skip a few
-        rom.write_byte(0x125, 0x17);
+        rom.write_byte(0x125, 0x17).unwrap();
 
         let actual = format!("{:?}", rom);
         let expected = "8068 8018 0000 0000 0000 0000 0000 0000\n0000 0000 0000 0000 0000 0000 0000 0000\n0000 0000 0017".to_string();
@@ -452,26 +842,31 @@ mod tests {
     #[test]
     fn macros_work() {
         let mut expected = Rom::new();
-        expected.write_byte(0x100, 0xa0);
-        expected.write_byte(0x101, 0x12);
-        expected.write_byte(0x102, 0x34);
-        expected.write_byte(0x103, 0x80);
-        expected.write_byte(0x104, 0x18);
-        expected.write_byte(0x105, 0x17);
+        expected.write_byte(0x100, 0xa0).unwrap();
+        expected.write_byte(0x101, 0x12).unwrap();
+        expected.write_byte(0x102, 0x34).unwrap();
+        expected.write_byte(0x103, 0x80).unwrap();
+        expected.write_byte(0x104, 0x18).unwrap();
+        expected.write_byte(0x105, 0x17).unwrap();
 
         let mut chunks = vec![
-            Ok(Chunk::new(String::from("%EMIT"), 0, 0)),
-            Ok(Chunk::new(String::from("{"), 0, 6)),
-            Ok(Chunk::new(String::from("#18"), 0, 8)),
-            Ok(Chunk::new(String::from("DEO"), 0, 12)),
-            Ok(Chunk::new(String::from("}"), 0, 15)),
-            Ok(Chunk::new(String::from("#1234"), 0, 17)),
-            Ok(Chunk::new(String::from("EMIT"), 0, 23)),
+            Ok(Chunk::new(String::from("%EMIT"), 0, 0, 0)),
+            Ok(Chunk::new(String::from("{"), 0, 6, 6)),
+            Ok(Chunk::new(String::from("#18"), 0, 8, 8)),
+            Ok(Chunk::new(String::from("DEO"), 0, 12, 12)),
+            Ok(Chunk::new(String::from("}"), 0, 15, 15)),
+            Ok(Chunk::new(String::from("#1234"), 0, 17, 17)),
+            Ok(Chunk::new(String::from("EMIT"), 0, 23, 23)),
         ]
         .into_iter();
-        let result = parse_chunks(Path::new(""), PathBuf::new(), &mut chunks);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
         assert!(result.is_ok());
-        let rom = result.unwrap();
+        let rom = result.unwrap().rom;
         assert_eq!(rom, expected);
     }
 
@@ -479,15 +874,22 @@ mod tests {
         let mut expected_rom = Rom::new();
         let bytes = hex::decode(expected.replace(" ", "")).unwrap();
         for (i, byte) in bytes.iter().enumerate() {
-            expected_rom.write_byte((0x100 + i).try_into().unwrap(), *byte);
+            expected_rom
+                .write_byte((0x100 + i).try_into().unwrap(), *byte)
+                .unwrap();
         }
 
         let mut buffer = Cursor::new(input);
-        let mut chunks = Chunker::new(&mut buffer);
+        let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
 
-        let result = parse_chunks(Path::new("not-a-file.tal"), PathBuf::new(), &mut chunks);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
         assert!(result.is_ok());
-        let rom = result.unwrap();
+        let rom = result.unwrap().rom;
         assert_eq!(rom, expected_rom);
     }
 
@@ -531,4 +933,387 @@ mod tests {
 
         assert_match(input, expected);
     }
+
+    #[test]
+    fn lambdas_work() {
+        assert_match("|0100 ?{ #01 } #02", "20 0002 8001 8002");
+        assert_match("|0100 { 12 } STH2r", "60 0001 12 6f");
+        assert_match("|0100 !{ { 12 } }", "40 0004 60 0001 12");
+    }
+
+    #[test]
+    fn lambdas_inside_macros_work() {
+        assert_match("%M { ?{ 12 } } |0100 M M", "20 0001 12 20 0001 12");
+    }
+
+    #[test]
+    fn it_reports_every_error() {
+        let mut buffer = Cursor::new("|0100 #123 ;foo @ ;bar #12");
+        let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
+        let errors = result.unwrap_err();
+        let expected = vec![
+            Error::new(
+                "could not parse byte or short".to_string(),
+                Chunk::new("#123".to_string(), 0, 6, 6),
+                PathBuf::new(),
+            )
+            .with_code("E0009"),
+            Error::new(
+                "empty label parent".to_string(),
+                Chunk::new("@".to_string(), 0, 16, 16),
+                PathBuf::new(),
+            )
+            .with_code("E0009"),
+            Error::new(
+                "unknown name \"foo\"".to_string(),
+                Chunk::new(";foo".to_string(), 0, 11, 11),
+                PathBuf::new(),
+            )
+            .with_code("E0012"),
+            Error::new(
+                "unknown name \"bar\"".to_string(),
+                Chunk::new(";bar".to_string(), 0, 18, 18),
+                PathBuf::new(),
+            )
+            .with_code("E0012"),
+        ];
+        assert_eq!(errors, expected);
+    }
+
+    #[test]
+    fn it_caps_the_number_of_errors() {
+        for (count, expected) in [
+            (MAX_ERRORS, MAX_ERRORS),
+            (MAX_ERRORS + 1, MAX_ERRORS + 1),
+            (MAX_ERRORS * 2, MAX_ERRORS + 1),
+        ] {
+            let input = "|0100 ".to_string() + &";foo ".repeat(count);
+            let mut buffer = Cursor::new(input);
+            let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+            let result = parse_chunks(
+                &FileSystemResolver::default(),
+                PathBuf::new(),
+                &ParseOptions::default(),
+                &mut chunks,
+            );
+            assert_eq!(result.unwrap_err().len(), expected);
+        }
+    }
+
+    #[test]
+    fn duplicate_labels_fail() {
+        let mut buffer = Cursor::new("|0100 @foo &bar #12 @foo &bar");
+        let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
+        let expected = vec![
+            Error::new(
+                "label \"foo\" is already defined".to_string(),
+                Chunk::new("@foo".to_string(), 0, 20, 20),
+                PathBuf::new(),
+            )
+            .with_code("E0011")
+            .with_label(
+                "first defined here".to_string(),
+                Chunk::new("@foo".to_string(), 0, 6, 6),
+                PathBuf::new(),
+            ),
+            Error::new(
+                "label \"foo/bar\" is already defined".to_string(),
+                Chunk::new("&bar".to_string(), 0, 25, 25),
+                PathBuf::new(),
+            )
+            .with_code("E0011")
+            .with_label(
+                "first defined here".to_string(),
+                Chunk::new("&bar".to_string(), 0, 11, 11),
+                PathBuf::new(),
+            ),
+        ];
+        assert_eq!(result.unwrap_err(), expected);
+    }
+
+    #[test]
+    fn unused_labels_warn() {
+        let input = "|00 @Device &port $1 |0100 @main ;used JSR2 BRK @used &loop ,&loop JMP @unused &child JMP2r";
+        let mut buffer = Cursor::new(input);
+        let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+        let options = ParseOptions {
+            warn_unused: true,
+            ..ParseOptions::default()
+        };
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &options,
+            &mut chunks,
+        );
+        let expected = vec![
+            Error::warning(
+                "label \"unused\" is never used".to_string(),
+                Chunk::new("@unused".to_string(), 0, 71, 71),
+                PathBuf::new(),
+            )
+            .with_code("W0001")
+            .with_help("remove the label, or reference it".to_string()),
+            Error::warning(
+                "label \"unused/child\" is never used".to_string(),
+                Chunk::new("&child".to_string(), 0, 79, 79),
+                PathBuf::new(),
+            )
+            .with_code("W0001")
+            .with_help("remove the label, or reference it".to_string()),
+        ];
+        assert_eq!(result.unwrap().warnings, expected);
+    }
+
+    #[test]
+    fn labels_used_by_padding_do_not_warn() {
+        let input = "|00 @Point &x $1 &size |0100 BRK @data #12 |data #34 $Point/size BRK";
+        let mut buffer = Cursor::new(input);
+        let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+        let options = ParseOptions {
+            warn_unused: true,
+            ..ParseOptions::default()
+        };
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &options,
+            &mut chunks,
+        );
+        assert_eq!(result.unwrap().warnings, []);
+    }
+
+    #[test]
+    fn padding_to_labels_works() {
+        // Overlaying a region by rewinding to a label
+        assert_match("|0100 @data #12 |data #34", "8034");
+        assert_match("|0100 @data &b #12 #34 |&b 56", "5612 8034");
+        // A label's address as a size, as in zero-page struct layouts
+        assert_match(
+            "|00 @Point &x $1 &y $1 &size |0100 $Point/size 12",
+            "0000 12",
+        );
+    }
+
+    #[test]
+    fn padding_to_unplaced_labels_fails() {
+        let mut buffer = Cursor::new("|0100 |later #12 @later $missing");
+        let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
+        let expected = vec![
+            Error::new(
+                "cannot pad to \"later\" before it is defined".to_string(),
+                Chunk::new("|later".to_string(), 0, 6, 6),
+                PathBuf::new(),
+            )
+            .with_code("E0017")
+            .with_label(
+                "defined here".to_string(),
+                Chunk::new("@later".to_string(), 0, 17, 17),
+                PathBuf::new(),
+            )
+            .with_help("move the padding after the label, or pad to a number".to_string()),
+            Error::new(
+                "unknown name \"missing\"".to_string(),
+                Chunk::new("$missing".to_string(), 0, 24, 24),
+                PathBuf::new(),
+            )
+            .with_code("E0012"),
+        ];
+        assert_eq!(result.unwrap_err(), expected);
+    }
+
+    #[test]
+    fn relative_references_at_the_limit_work() {
+        assert_match("|0100 ,foo JMP $7f @foo", "807f 0c");
+        assert_match("|0100 @foo $7d ,foo JMP", &("00".repeat(0x7d) + "8080 0c"));
+    }
+
+    #[test]
+    fn immediate_jumps_reach_any_address() {
+        let mut buffer = Cursor::new("|0100 !far @back BRK |8400 @far ?back #12");
+        let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
+        let rom = result.unwrap().rom;
+        assert_eq!(rom.get_bytes()[..3], [0x40, 0x82, 0xfd]);
+        assert_eq!(rom.get_bytes()[0x8300..], [0x20, 0x7d, 0x00, 0x80, 0x12]);
+    }
+
+    #[test]
+    fn relative_references_out_of_range_fail() {
+        let mut buffer = Cursor::new("|0100 ,foo JMP $100 @foo");
+        let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
+        let expected = vec![
+            Error::new(
+                "relative reference to \"foo\" is too far away, distance 256 does not fit in a signed byte".to_string(),
+                Chunk::new(",foo".to_string(), 0, 6, 6),
+                PathBuf::new(),
+            )
+            .with_code("E0013")
+            .with_help("use an absolute reference such as ;foo instead".to_string()),
+        ];
+        assert_eq!(result.unwrap_err(), expected);
+    }
+
+    #[test]
+    fn zero_page_references_outside_the_zero_page_fail() {
+        let mut buffer = Cursor::new("|0100 .foo LDZ -foo\n@foo");
+        let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
+        let message =
+            "zero-page reference to \"foo\" resolves to 0x0104, which is outside the zero page";
+        let error = |chunk| {
+            Error::new(message.to_string(), chunk, PathBuf::new())
+                .with_code("E0014")
+                .with_label(
+                    "\"foo\" is defined here".to_string(),
+                    Chunk::new("@foo".to_string(), 1, 0, 20),
+                    PathBuf::new(),
+                )
+                .with_help("use an absolute reference such as ;foo instead".to_string())
+        };
+        let expected = vec![
+            error(Chunk::new(".foo".to_string(), 0, 6, 6)),
+            error(Chunk::new("-foo".to_string(), 0, 15, 15)),
+        ];
+        assert_eq!(result.unwrap_err(), expected);
+    }
+
+    #[test]
+    fn writing_to_the_zero_page_fails() {
+        let mut buffer = Cursor::new("|00 #12 |0100 #34");
+        let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
+        let expected = vec![Error::new(
+            "cannot write to the zero page at 0x0000, code and data must start at 0x0100"
+                .to_string(),
+            Chunk::new("#12".to_string(), 0, 4, 4),
+            PathBuf::new(),
+        )
+        .with_code("E0015")
+        .with_help("add |0100 before the first instruction or data".to_string())];
+        assert_eq!(result.unwrap_err(), expected);
+    }
+
+    #[test]
+    fn writing_past_the_end_of_memory_fails() {
+        let cases = [
+            ("|fffe #1234", Chunk::new("#1234".to_string(), 0, 6, 6)),
+            ("|ffff $1 @foo", Chunk::new("@foo".to_string(), 0, 9, 9)),
+            (
+                "|fff0 $10 #12 #34",
+                Chunk::new("#12".to_string(), 0, 10, 10),
+            ),
+        ];
+        for (input, chunk) in cases {
+            let mut buffer = Cursor::new(input);
+            let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+            let result = parse_chunks(
+                &FileSystemResolver::default(),
+                PathBuf::new(),
+                &ParseOptions::default(),
+                &mut chunks,
+            );
+            let expected = vec![Error::new(
+                "ROM too large, it does not fit in 64KB".to_string(),
+                chunk,
+                PathBuf::new(),
+            )
+            .with_code("E0016")];
+            assert_eq!(result.unwrap_err(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn writing_the_last_byte_works() {
+        let mut buffer = Cursor::new("|fffe #12");
+        let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+        let result = parse_chunks(
+            &FileSystemResolver::default(),
+            PathBuf::new(),
+            &ParseOptions::default(),
+            &mut chunks,
+        );
+        let rom = result.unwrap().rom;
+        assert_eq!(rom.get_bytes().len(), 0xff00);
+        assert_eq!(rom.get_bytes()[0xfefe..], [0x80, 0x12]);
+    }
+
+    #[test]
+    fn trailing_zeros_are_trimmed() {
+        for (no_trim, bytes, trimmed) in [
+            (false, vec![0x80, 0x12], 3),
+            (true, vec![0x80, 0x12, 0x00, 0x00, 0x00], 0),
+        ] {
+            let mut buffer = Cursor::new("|0100 #12 00 $1 00");
+            let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+            let options = ParseOptions {
+                no_trim,
+                ..ParseOptions::default()
+            };
+            let assembly = parse_chunks(
+                &FileSystemResolver::default(),
+                PathBuf::new(),
+                &options,
+                &mut chunks,
+            )
+            .unwrap();
+            assert_eq!(assembly.rom.get_bytes(), bytes);
+            assert_eq!(assembly.trimmed, trimmed);
+            assert_eq!(assembly.rom.get_written_bytes().len(), 5);
+        }
+    }
+
+    #[test]
+    fn unbalanced_lambdas_fail() {
+        for input in ["|0100 { 12", "|0100 12 }"] {
+            let mut buffer = Cursor::new(input);
+            let mut chunks = Chunker::new(PathBuf::new(), &mut buffer);
+            let result = parse_chunks(
+                &FileSystemResolver::default(),
+                PathBuf::new(),
+                &ParseOptions::default(),
+                &mut chunks,
+            );
+            assert!(result.is_err(), "{input}");
+        }
+    }
 }
@@ -11,7 +11,7 @@ pub struct PreProcessBrackets<'a> {
 }
 
 impl PreProcessBrackets<'_> {
-    pub fn new(chunks: &mut dyn Iterator<Item = Result<Chunk, Error>>) -> PreProcessBrackets {
+    pub fn new(chunks: &mut dyn Iterator<Item = Result<Chunk, Error>>) -> PreProcessBrackets<'_> {
         PreProcessBrackets {
             chunks,
             replacement: vec![],
@@ -50,16 +50,16 @@ mod tests {
     #[test]
     fn it_works() {
         let mut source = vec![
-            Ok(Chunk::new(String::from("~hello.tal"), 0, 0)),
-            Ok(Chunk::new(String::from("["), 0, 0)),
-            Ok(Chunk::new(String::from("]"), 0, 0)),
+            Ok(Chunk::new(String::from("~hello.tal"), 0, 0, 0)),
+            Ok(Chunk::new(String::from("["), 0, 0, 0)),
+            Ok(Chunk::new(String::from("]"), 0, 0, 0)),
         ]
         .into_iter();
         let mut pp = PreProcessBrackets::new(&mut source);
 
         assert_eq!(
             pp.next(),
-            Some(Ok(Chunk::new(String::from("~hello.tal"), 0, 0)))
+            Some(Ok(Chunk::new(String::from("~hello.tal"), 0, 0, 0)))
         );
         assert_eq!(pp.next(), None);
     }
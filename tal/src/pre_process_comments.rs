@@ -29,7 +29,7 @@ impl PreProcessComments<'_> {
     pub fn new(
         file: PathBuf,
         chunks: &mut dyn Iterator<Item = Result<Chunk, Error>>,
-    ) -> PreProcessComments {
+    ) -> PreProcessComments<'_> {
         PreProcessComments {
             file,
             chunks,
@@ -63,12 +63,16 @@ impl Iterator for PreProcessComments<'_> {
                 return Some(Ok(chunk));
             }
 
-            if next.is_none() && self.comment_start.is_some() {
-                return Some(Err(Error::new(
-                    "reached EOF without finding comment close".to_string(),
-                    self.comment_start.clone().unwrap(),
-                    self.file.clone(),
-                )));
+            // Only reported once, the comment is over either way
+            if next.is_none() {
+                if let Some(comment_start) = self.comment_start.take() {
+                    return Some(Err(Error::new(
+                        "reached EOF without finding comment close".to_string(),
+                        comment_start,
+                        self.file.clone(),
+                    )
+                    .with_code("E0002")));
+                }
             }
 
             return next;
@@ -83,39 +87,50 @@ mod tests {
     #[test]
     fn it_works() {
         let mut source = vec![
-            Ok(Chunk::new(String::from("cat"), 0, 0)),
-            Ok(Chunk::new(String::from("("), 0, 0)),
-            Ok(Chunk::new(String::from("woof"), 0, 0)),
-            Ok(Chunk::new(String::from(")"), 0, 0)),
-            Ok(Chunk::new(String::from("dog"), 0, 0)),
+            Ok(Chunk::new(String::from("cat"), 0, 0, 0)),
+            Ok(Chunk::new(String::from("("), 0, 0, 0)),
+            Ok(Chunk::new(String::from("woof"), 0, 0, 0)),
+            Ok(Chunk::new(String::from(")"), 0, 0, 0)),
+            Ok(Chunk::new(String::from("dog"), 0, 0, 0)),
         ]
         .into_iter();
         let mut pp = PreProcessComments::new(PathBuf::new(), &mut source);
 
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("cat"), 0, 0))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("dog"), 0, 0))));
+        assert_eq!(
+            pp.next(),
+            Some(Ok(Chunk::new(String::from("cat"), 0, 0, 0)))
+        );
+        assert_eq!(
+            pp.next(),
+            Some(Ok(Chunk::new(String::from("dog"), 0, 0, 0)))
+        );
         assert_eq!(pp.next(), None);
     }
 
     #[test]
     fn it_fails() {
         let mut source = vec![
-            Ok(Chunk::new(String::from("cat"), 0, 0)),
-            Ok(Chunk::new(String::from("("), 0, 0)),
-            Ok(Chunk::new(String::from("woof"), 0, 0)),
-            Ok(Chunk::new(String::from("dog"), 0, 0)),
+            Ok(Chunk::new(String::from("cat"), 0, 0, 0)),
+            Ok(Chunk::new(String::from("("), 0, 0, 0)),
+            Ok(Chunk::new(String::from("woof"), 0, 0, 0)),
+            Ok(Chunk::new(String::from("dog"), 0, 0, 0)),
         ]
         .into_iter();
         let mut pp = PreProcessComments::new(PathBuf::new(), &mut source);
 
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("cat"), 0, 0))));
+        assert_eq!(
+            pp.next(),
+            Some(Ok(Chunk::new(String::from("cat"), 0, 0, 0)))
+        );
         assert_eq!(
             pp.next(),
             Some(Err(Error::new(
                 "reached EOF without finding comment close".to_string(),
-                Chunk::new("(".to_string(), 0, 0),
+                Chunk::new("(".to_string(), 0, 0, 0),
                 PathBuf::new(),
-            )))
+            )
+            .with_code("E0002")))
         );
+        assert_eq!(pp.next(), None);
     }
 }
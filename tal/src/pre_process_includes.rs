@@ -1,33 +1,134 @@
 use crate::chunker::Chunk;
+use crate::chunker::Chunker;
+use crate::chunker::Include;
 use crate::error::Error;
-use crate::parser::chunk_file;
+use crate::include_resolver::IncludeResolver;
+use crate::parser::pre_process;
+use std::io::Cursor;
+use std::path::Component;
 use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
 
 fn include_path_from_chunk(chunk: &Chunk) -> Option<String> {
-    if &chunk.value[0..1] == "~" {
+    if chunk.value.starts_with('~') {
         Some(chunk.value[1..].to_string())
     } else {
         None
     }
 }
 
+// Removes . and .. components without touching the filesystem, so that a file reached through
+// different paths is still recognised as the same file
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                _ => normalized.push(".."),
+            },
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
 pub struct PreProcessIncludes<'a> {
-    cwd: &'a Path,
+    resolver: &'a dyn IncludeResolver,
+    file: PathBuf,
+    // The files that included this one, outermost first, with the chunks that included them
+    includes: Vec<(PathBuf, Chunk)>,
+    // Attached to every chunk read from this file, None for the file being assembled
+    source: Option<Rc<Include>>,
     chunks: &'a mut dyn Iterator<Item = Result<Chunk, Error>>,
     replacement: Vec<Result<Chunk, Error>>,
 }
 
 impl PreProcessIncludes<'_> {
     pub fn new<'a>(
-        cwd: &'a Path,
+        resolver: &'a dyn IncludeResolver,
+        file: PathBuf,
+        includes: Vec<(PathBuf, Chunk)>,
         chunks: &'a mut dyn Iterator<Item = Result<Chunk, Error>>,
     ) -> PreProcessIncludes<'a> {
+        let source = if includes.is_empty() {
+            None
+        } else {
+            Some(Rc::new(Include {
+                path: file.clone(),
+                included_from: includes.clone(),
+            }))
+        };
         PreProcessIncludes {
-            cwd,
+            resolver,
+            file,
+            includes,
+            source,
             chunks,
             replacement: vec![],
         }
     }
+
+    fn include(&self, chunk: &Chunk, path: &Path) -> Vec<Result<Chunk, Error>> {
+        // Look next to the including file first, then fall back to the working directory
+        let mut candidates = vec![];
+        if let Some(directory) = self.file.parent() {
+            candidates.push(normalize(&directory.join(path)));
+        }
+        if !candidates.contains(&normalize(path)) {
+            candidates.push(normalize(path));
+        }
+
+        let mut error = String::new();
+        for candidate in candidates {
+            match self.resolver.resolve(&candidate) {
+                Ok(source) => return self.include_source(chunk, candidate, source),
+                Err(err) => error = err,
+            }
+        }
+
+        vec![Err(Error::new(
+            format!("could not include \"{}\": {}", path.display(), error),
+            chunk.clone(),
+            self.file.clone(),
+        )
+        .with_code("E0003"))]
+    }
+
+    fn include_source(
+        &self,
+        chunk: &Chunk,
+        path: PathBuf,
+        source: Vec<u8>,
+    ) -> Vec<Result<Chunk, Error>> {
+        let mut includes = self.includes.clone();
+        includes.push((self.file.clone(), chunk.clone()));
+
+        if includes.iter().any(|(file, _)| normalize(file) == path) {
+            let chain: Vec<String> = includes
+                .iter()
+                .map(|(file, chunk)| format!("{}:{}", file.display(), chunk.line + 1))
+                .collect();
+            return vec![Err(Error::new(
+                format!(
+                    "include cycle: {} -> {}",
+                    chain.join(" -> "),
+                    path.display()
+                ),
+                chunk.clone(),
+                self.file.clone(),
+            )
+            .with_code("E0004"))];
+        }
+
+        let mut input = Cursor::new(source);
+        let mut chunker = Chunker::new(path.clone(), &mut input);
+        pre_process(self.resolver, path, includes, &mut chunker)
+    }
 }
 
 impl Iterator for PreProcessIncludes<'_> {
@@ -38,12 +139,19 @@ impl Iterator for PreProcessIncludes<'_> {
             let next = if !self.replacement.is_empty() {
                 Some(self.replacement.remove(0))
             } else {
-                self.chunks.next()
+                let source = &self.source;
+                self.chunks.next().map(|next| match next {
+                    Ok(chunk) => Ok(Chunk {
+                        include: source.clone(),
+                        ..chunk
+                    }),
+                    Err(err) => Err(err.with_include(source)),
+                })
             };
 
             if let Some(Ok(chunk)) = next {
                 if let Some(path) = include_path_from_chunk(&chunk) {
-                    self.replacement = chunk_file(self.cwd, Path::new(&path));
+                    self.replacement = self.include(&chunk, Path::new(&path));
                     continue;
                 }
 
@@ -58,35 +166,186 @@ impl Iterator for PreProcessIncludes<'_> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::include_resolver::FileSystemResolver;
+    use crate::include_resolver::MemoryResolver;
     use std::env::current_dir;
 
+    fn chunk(
+        value: &str,
+        line: usize,
+        column: usize,
+        offset: usize,
+        file: &str,
+    ) -> (String, usize, usize, usize, PathBuf) {
+        (value.to_string(), line, column, offset, PathBuf::from(file))
+    }
+
+    // The next chunk's value, position and file, leaving out the chain of includes
+    fn next(pp: &mut PreProcessIncludes) -> Option<(String, usize, usize, usize, PathBuf)> {
+        pp.next().map(|chunk| {
+            let chunk = chunk.unwrap();
+            let file = chunk.file(Path::new("main.tal")).to_path_buf();
+            (chunk.value, chunk.line, chunk.column, chunk.offset, file)
+        })
+    }
+
     #[test]
     fn it_works() {
-        let cwd = current_dir().unwrap().join("tests/roms");
-        let mut source = vec![Ok(Chunk::new(String::from("~hello.tal"), 0, 0))].into_iter();
-        let mut pp = PreProcessIncludes::new(&cwd, &mut source);
-
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("|0100"), 1, 0))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("LIT"), 1, 6))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("68"), 1, 10))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("LIT"), 1, 13))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("18"), 1, 17))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("DEO"), 1, 20))));
+        let resolver = FileSystemResolver::new(current_dir().unwrap().join("tests/roms"));
+        let mut source = vec![Ok(Chunk::new(String::from("~hello.tal"), 0, 0, 0))].into_iter();
+        let mut pp = PreProcessIncludes::new(&resolver, PathBuf::new(), vec![], &mut source);
+
+        assert_eq!(next(&mut pp), Some(chunk("|0100", 1, 0, 52, "hello.tal")));
+        assert_eq!(next(&mut pp), Some(chunk("LIT", 1, 6, 58, "hello.tal")));
+        assert_eq!(next(&mut pp), Some(chunk("68", 1, 10, 62, "hello.tal")));
+        assert_eq!(next(&mut pp), Some(chunk("LIT", 1, 13, 65, "hello.tal")));
+        assert_eq!(next(&mut pp), Some(chunk("18", 1, 17, 69, "hello.tal")));
+        assert_eq!(next(&mut pp), Some(chunk("DEO", 1, 20, 72, "hello.tal")));
         assert_eq!(pp.next(), None);
     }
 
     #[test]
     fn includes_inside_includes_work() {
-        let cwd = current_dir().unwrap().join("tests/roms");
-        let mut source = vec![Ok(Chunk::new(String::from("~hello-include.tal"), 0, 0))].into_iter();
-        let mut pp = PreProcessIncludes::new(&cwd, &mut source);
-
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("|0100"), 1, 0))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("LIT"), 1, 6))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("68"), 1, 10))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("LIT"), 1, 13))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("18"), 1, 17))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("DEO"), 1, 20))));
+        let resolver = FileSystemResolver::new(current_dir().unwrap().join("tests/roms"));
+        let mut source =
+            vec![Ok(Chunk::new(String::from("~hello-include.tal"), 0, 0, 0))].into_iter();
+        let mut pp = PreProcessIncludes::new(&resolver, PathBuf::new(), vec![], &mut source);
+
+        assert_eq!(next(&mut pp), Some(chunk("|0100", 1, 0, 52, "hello.tal")));
+        assert_eq!(next(&mut pp), Some(chunk("LIT", 1, 6, 58, "hello.tal")));
+        assert_eq!(next(&mut pp), Some(chunk("68", 1, 10, 62, "hello.tal")));
+        assert_eq!(next(&mut pp), Some(chunk("LIT", 1, 13, 65, "hello.tal")));
+        assert_eq!(next(&mut pp), Some(chunk("18", 1, 17, 69, "hello.tal")));
+        assert_eq!(next(&mut pp), Some(chunk("DEO", 1, 20, 72, "hello.tal")));
+        assert_eq!(pp.next(), None);
+    }
+
+    #[test]
+    fn includes_from_memory_work() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("lib.tal"), "( lib ) #12\nDEO".to_string());
+        let mut source = vec![Ok(Chunk::new(String::from("~lib.tal"), 0, 0, 0))].into_iter();
+        let mut pp = PreProcessIncludes::new(&resolver, PathBuf::new(), vec![], &mut source);
+
+        assert_eq!(next(&mut pp), Some(chunk("#12", 0, 8, 8, "lib.tal")));
+        assert_eq!(next(&mut pp), Some(chunk("DEO", 1, 0, 12, "lib.tal")));
+        assert_eq!(pp.next(), None);
+    }
+
+    #[test]
+    fn missing_includes_fail() {
+        let resolver = MemoryResolver::new();
+        let mut source = vec![Ok(Chunk::new(String::from("~missing.tal"), 2, 4, 6))].into_iter();
+        let mut pp =
+            PreProcessIncludes::new(&resolver, PathBuf::from("main.tal"), vec![], &mut source);
+
+        assert_eq!(
+            pp.next(),
+            Some(Err(Error::new(
+                "could not include \"missing.tal\": no such file".to_string(),
+                Chunk::new(String::from("~missing.tal"), 2, 4, 6),
+                PathBuf::from("main.tal"),
+            )
+            .with_code("E0003")))
+        );
+        assert_eq!(pp.next(), None);
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("lib/a.tal"), "~b.tal".to_string());
+        resolver.insert(PathBuf::from("lib/b.tal"), "~../c.tal".to_string());
+        resolver.insert(PathBuf::from("c.tal"), "~lib/d.tal".to_string());
+        resolver.insert(PathBuf::from("lib/d.tal"), "#12".to_string());
+        let mut source = vec![Ok(Chunk::new(String::from("~lib/a.tal"), 0, 0, 0))].into_iter();
+        let mut pp =
+            PreProcessIncludes::new(&resolver, PathBuf::from("main.tal"), vec![], &mut source);
+
+        assert_eq!(next(&mut pp), Some(chunk("#12", 0, 0, 0, "lib/d.tal")));
+        assert_eq!(pp.next(), None);
+    }
+
+    #[test]
+    fn includes_fall_back_to_the_working_directory() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("lib/a.tal"), "~other/b.tal".to_string());
+        resolver.insert(PathBuf::from("other/b.tal"), "#12".to_string());
+        let mut source = vec![Ok(Chunk::new(String::from("~lib/a.tal"), 0, 0, 0))].into_iter();
+        let mut pp =
+            PreProcessIncludes::new(&resolver, PathBuf::from("main.tal"), vec![], &mut source);
+
+        assert_eq!(next(&mut pp), Some(chunk("#12", 0, 0, 0, "other/b.tal")));
         assert_eq!(pp.next(), None);
     }
+
+    #[test]
+    fn include_cycles_fail() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("a.tal"), "#12\n~b.tal".to_string());
+        resolver.insert(PathBuf::from("b.tal"), "~./a.tal".to_string());
+        let mut source = vec![Ok(Chunk::new(String::from("~a.tal"), 2, 0, 2))].into_iter();
+        let mut pp =
+            PreProcessIncludes::new(&resolver, PathBuf::from("main.tal"), vec![], &mut source);
+
+        assert_eq!(next(&mut pp), Some(chunk("#12", 0, 0, 0, "a.tal")));
+        let error = pp.next().unwrap().unwrap_err();
+        assert_eq!(
+            error.message(),
+            "include cycle: main.tal:3 -> a.tal:2 -> b.tal:1 -> a.tal"
+        );
+        assert_eq!(error.chunk().value, "~./a.tal");
+        assert_eq!(error.file(), Path::new("b.tal"));
+        assert!(pp.next().is_none());
+    }
+
+    #[test]
+    fn including_yourself_fails() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("a.tal"), "~a.tal".to_string());
+        let mut source = vec![Ok(Chunk::new(String::from("~a.tal"), 0, 0, 0))].into_iter();
+        let mut pp =
+            PreProcessIncludes::new(&resolver, PathBuf::from("main.tal"), vec![], &mut source);
+
+        let error = pp.next().unwrap().unwrap_err();
+        assert_eq!(
+            error.message(),
+            "include cycle: main.tal:1 -> a.tal:1 -> a.tal"
+        );
+        assert_eq!(error.file(), Path::new("a.tal"));
+    }
+
+    #[test]
+    fn included_chunks_remember_where_they_were_included_from() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("a.tal"), "\n~b.tal".to_string());
+        resolver.insert(PathBuf::from("b.tal"), "#12".to_string());
+        let mut source = vec![
+            Ok(Chunk::new(String::from("#34"), 0, 0, 0)),
+            Ok(Chunk::new(String::from("~a.tal"), 0, 4, 4)),
+        ]
+        .into_iter();
+        let mut pp =
+            PreProcessIncludes::new(&resolver, PathBuf::from("main.tal"), vec![], &mut source);
+
+        assert_eq!(
+            pp.next(),
+            Some(Ok(Chunk::new(String::from("#34"), 0, 0, 0)))
+        );
+        let chunk = pp.next().unwrap().unwrap();
+        let include = chunk.include.unwrap();
+        assert_eq!(include.path, PathBuf::from("b.tal"));
+        let included_from: Vec<(&Path, &str, usize)> = include
+            .included_from
+            .iter()
+            .map(|(file, chunk)| (file.as_path(), chunk.value.as_str(), chunk.line))
+            .collect();
+        assert_eq!(
+            included_from,
+            vec![
+                (Path::new("main.tal"), "~a.tal", 0),
+                (Path::new("a.tal"), "~b.tal", 1)
+            ]
+        );
+    }
 }
@@ -1,18 +1,34 @@
 use crate::chunker::Chunk;
+use crate::chunker::Expansion;
 use crate::error::Error;
+use crate::opcode::Opcode;
+use crate::token::parse_byte;
+use crate::token::parse_short;
 use std::collections::HashMap;
+use std::mem::replace;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
+
+// Macros can invoke other macros, but not this many deep
+pub const MAX_MACRO_DEPTH: usize = 64;
 
 #[derive(PartialEq)]
 enum MacroToken {
     MacroDefinition(String),
     MacroStart,
     MacroEnd,
+    LambdaStart,
     Other,
 }
 
 impl MacroToken {
     fn from_chunk(chunk: &Chunk) -> MacroToken {
-        match &chunk.value[0..1] {
+        match &chunk.value[..] {
+            "?{" | "!{" => return MacroToken::LambdaStart,
+            _ => {}
+        }
+        match chunk.value.get(0..1).unwrap_or_default() {
             "%" => MacroToken::MacroDefinition(chunk.value[1..].to_string()),
             "{" => MacroToken::MacroStart,
             "}" => MacroToken::MacroEnd,
@@ -21,29 +37,122 @@ impl MacroToken {
     }
 }
 
+struct Definition {
+    // The %NAME chunk
+    chunk: Chunk,
+    body: Vec<Chunk>,
+}
+
 #[allow(clippy::enum_variant_names)]
 enum MacroState {
     WaitingForName,
-    WaitingForOpen(String),
-    WaitingForClose(String),
+    // bool: Whether to keep the definition once it's complete, false if the name was rejected
+    WaitingForOpen(String, Definition, bool),
+    // usize: How many lambdas inside the macro body are still open
+    WaitingForClose(String, Definition, bool, usize),
+}
+
+fn location(chunk: &Chunk) -> String {
+    format!("line {} column {}", chunk.line + 1, chunk.column + 1)
+}
+
+// The macros that were invoked to produce chunk, outermost first
+fn expansion_chain(chunk: &Chunk) -> Vec<&Expansion> {
+    let mut chain = vec![];
+    let mut expansion = &chunk.expansion;
+    while let Some(frame) = expansion {
+        chain.insert(0, frame.as_ref());
+        expansion = &frame.chunk.expansion;
+    }
+    chain
 }
 
 pub struct PreProcessMacros<'a> {
+    file: PathBuf,
     chunks: &'a mut dyn Iterator<Item = Result<Chunk, Error>>,
     macro_state: MacroState,
-    macro_definitions: HashMap<String, Vec<Chunk>>,
+    macro_definitions: HashMap<String, Definition>,
     replacement: Vec<Chunk>,
 }
 
 impl PreProcessMacros<'_> {
-    pub fn new(chunks: &mut dyn Iterator<Item = Result<Chunk, Error>>) -> PreProcessMacros {
+    pub fn new(
+        file: PathBuf,
+        chunks: &mut dyn Iterator<Item = Result<Chunk, Error>>,
+    ) -> PreProcessMacros<'_> {
         PreProcessMacros {
+            file,
             chunks,
             macro_state: MacroState::WaitingForName,
             macro_definitions: HashMap::new(),
             replacement: vec![],
         }
     }
+
+    fn error(&self, code: &'static str, message: String, chunk: &Chunk) -> Error {
+        let file = chunk.file(&self.file).to_path_buf();
+        Error::new(message, chunk.clone(), file).with_code(code)
+    }
+
+    // Macros are expanded before anything else, so a name that means something else would
+    // silently change what that something else assembles to
+    fn check_name(&self, name: &str, chunk: &Chunk) -> Option<Error> {
+        let message = if name.is_empty() {
+            "macro name is empty".to_string()
+        } else if Opcode::from_str(name).is_ok() {
+            format!("macro name \"{name}\" is an opcode")
+        } else if parse_byte(name).is_ok() || parse_short(name).is_ok() {
+            format!("macro name \"{name}\" is a hex literal")
+        } else if let Some(first) = self.macro_definitions.get(name) {
+            let file = first.chunk.file(&self.file).to_path_buf();
+            let message = format!("macro \"{name}\" is already defined");
+            return Some(self.error("E0006", message, chunk).with_label(
+                "first defined here".to_string(),
+                first.chunk.clone(),
+                file,
+            ));
+        } else {
+            return None;
+        };
+        Some(self.error("E0007", message, chunk))
+    }
+
+    // Queues up the body of the macro invoked by chunk, unless that would never end
+    fn expand(&mut self, chunk: Chunk) -> Option<Error> {
+        let chain = expansion_chain(&chunk);
+        let recursive = chain.iter().any(|frame| frame.name == chunk.value);
+        if recursive || chain.len() >= MAX_MACRO_DEPTH {
+            let mut frames: Vec<String> = chain
+                .iter()
+                .map(|frame| format!("{} at {}", frame.name, location(&frame.chunk)))
+                .collect();
+            frames.push(format!("{} at {}", chunk.value, location(&chunk)));
+            let message = if recursive {
+                format!("macro \"{}\" invokes itself: ", chunk.value)
+            } else {
+                format!("macros are nested more than {MAX_MACRO_DEPTH} deep: ")
+            };
+            return Some(self.error("E0008", message + &frames.join(" -> "), &chunk));
+        }
+
+        // Prepend the definition to the current replacement vec, remembering where it was
+        // expanded
+        let definition = self.macro_definitions.get(&chunk.value).unwrap();
+        let expansion = Rc::new(Expansion {
+            name: chunk.value.clone(),
+            chunk,
+        });
+        let body: Vec<Chunk> = definition
+            .body
+            .iter()
+            .map(|body| Chunk {
+                expansion: Some(expansion.clone()),
+                ..body.clone()
+            })
+            .collect();
+        self.replacement.splice(0..0, body);
+        None
+    }
 }
 
 impl Iterator for PreProcessMacros<'_> {
@@ -57,53 +166,91 @@ impl Iterator for PreProcessMacros<'_> {
                 self.chunks.next()
             };
 
-            if let Some(Ok(chunk)) = next {
-                let token = MacroToken::from_chunk(&chunk);
-                match &self.macro_state {
-                    MacroState::WaitingForName => {
-                        if let MacroToken::MacroDefinition(name) = token {
-                            self.macro_state = MacroState::WaitingForOpen(name.clone());
-                            continue;
-                        } else if token == MacroToken::Other {
-                            if let Some(definition) = self.macro_definitions.get(&chunk.value) {
-                                // We found a macro definition for this name
-                                // So prepend the definition to the current replacement vec
-                                self.replacement.splice(0..0, definition.to_vec());
-                                continue;
-                            } else {
-                                // We didn't find a macro definition for this name
-                                // So treat it like it's an instant invocation and allow it to
-                                // pass through instead
-                            }
+            let chunk = match next {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    // Anything still being defined was cut short
+                    return match replace(&mut self.macro_state, MacroState::WaitingForName) {
+                        MacroState::WaitingForName => None,
+                        MacroState::WaitingForOpen(name, definition, _) => Some(Err(self.error(
+                            "E0005",
+                            format!("reached EOF without finding the {{ for macro \"{name}\""),
+                            &definition.chunk,
+                        ))),
+                        MacroState::WaitingForClose(name, definition, _, _) => Some(Err(self
+                            .error(
+                                "E0005",
+                                format!("reached EOF without finding macro close for \"{name}\""),
+                                &definition.chunk,
+                            ))),
+                    };
+                }
+            };
+
+            let token = MacroToken::from_chunk(&chunk);
+            match replace(&mut self.macro_state, MacroState::WaitingForName) {
+                MacroState::WaitingForName => {
+                    if let MacroToken::MacroDefinition(name) = token {
+                        let error = self.check_name(&name, &chunk);
+                        let definition = Definition {
+                            chunk,
+                            body: vec![],
+                        };
+                        self.macro_state =
+                            MacroState::WaitingForOpen(name, definition, error.is_none());
+                        match error {
+                            Some(error) => return Some(Err(error)),
+                            None => continue,
                         }
-                    }
-                    MacroState::WaitingForOpen(name) => {
-                        if token == MacroToken::MacroStart {
-                            self.macro_definitions.insert(name.clone(), vec![]);
-                            self.macro_state = MacroState::WaitingForClose(name.clone());
-                            continue;
+                    } else if token == MacroToken::Other
+                        && self.macro_definitions.contains_key(&chunk.value)
+                    {
+                        match self.expand(chunk) {
+                            Some(error) => return Some(Err(error)),
+                            None => continue,
                         }
+                    } else {
+                        // We didn't find a macro definition for this name
+                        // So treat it like it's an instant invocation and allow it to
+                        // pass through instead
                     }
-                    MacroState::WaitingForClose(name) => {
-                        if token == MacroToken::MacroEnd {
-                            self.macro_state = MacroState::WaitingForName;
-                            continue;
-                        } else {
-                            let mut current_macro = vec![];
-                            for chunk in self.macro_definitions.get(name).unwrap() {
-                                current_macro.push(chunk.clone());
-                            }
-                            current_macro.push(chunk.clone());
-                            self.macro_definitions.insert(name.clone(), current_macro);
-                            continue;
-                        }
+                }
+                MacroState::WaitingForOpen(name, definition, keep) => {
+                    if token == MacroToken::MacroStart {
+                        self.macro_state = MacroState::WaitingForClose(name, definition, keep, 0);
+                        continue;
                     }
+                    // Carry on from this chunk as if the definition wasn't there
+                    self.replacement.insert(0, chunk);
+                    return Some(Err(self.error(
+                        "E0005",
+                        format!("macro \"{name}\" must be followed by {{ to open its body"),
+                        &definition.chunk,
+                    )));
                 }
+                MacroState::WaitingForClose(name, mut definition, keep, depth) => {
+                    if token == MacroToken::MacroEnd && depth == 0 {
+                        if keep {
+                            self.macro_definitions.insert(name, definition);
+                        }
+                        continue;
+                    }
 
-                return Some(Ok(chunk));
+                    // Braces inside the body belong to lambdas, so keep track of them
+                    // to find the } that actually closes the macro
+                    let depth = match token {
+                        MacroToken::MacroStart | MacroToken::LambdaStart => depth + 1,
+                        MacroToken::MacroEnd => depth - 1,
+                        _ => depth,
+                    };
+                    definition.body.push(chunk);
+                    self.macro_state = MacroState::WaitingForClose(name, definition, keep, depth);
+                    continue;
+                }
             }
 
-            return next;
+            return Some(Ok(chunk));
         }
     }
 }
@@ -114,26 +261,44 @@ mod tests {
     use crate::chunker::Chunker;
     use std::io::Cursor;
 
+    // A chunk of a macro body, as it appears once the macro has been invoked by invocation
+    fn expanded(
+        value: &str,
+        line: usize,
+        column: usize,
+        offset: usize,
+        invocation: &Chunk,
+    ) -> Chunk {
+        Chunk {
+            expansion: Some(Rc::new(Expansion {
+                name: invocation.value.clone(),
+                chunk: invocation.clone(),
+            })),
+            ..Chunk::new(value.to_string(), line, column, offset)
+        }
+    }
+
     #[test]
     fn it_works() {
         let mut source = vec![
-            Ok(Chunk::new(String::from("%EMIT"), 0, 0)),
-            Ok(Chunk::new(String::from("{"), 0, 6)),
-            Ok(Chunk::new(String::from("#18"), 0, 8)),
-            Ok(Chunk::new(String::from("DEO"), 0, 12)),
-            Ok(Chunk::new(String::from("}"), 0, 15)),
-            Ok(Chunk::new(String::from("#1234"), 0, 17)),
-            Ok(Chunk::new(String::from("EMIT"), 0, 23)),
+            Ok(Chunk::new(String::from("%EMIT"), 0, 0, 0)),
+            Ok(Chunk::new(String::from("{"), 0, 6, 6)),
+            Ok(Chunk::new(String::from("#18"), 0, 8, 8)),
+            Ok(Chunk::new(String::from("DEO"), 0, 12, 12)),
+            Ok(Chunk::new(String::from("}"), 0, 15, 15)),
+            Ok(Chunk::new(String::from("#1234"), 0, 17, 17)),
+            Ok(Chunk::new(String::from("EMIT"), 0, 23, 23)),
         ]
         .into_iter();
-        let mut pp = PreProcessMacros::new(&mut source);
+        let mut pp = PreProcessMacros::new(PathBuf::new(), &mut source);
+        let emit = Chunk::new(String::from("EMIT"), 0, 23, 23);
 
         assert_eq!(
             pp.next(),
-            Some(Ok(Chunk::new(String::from("#1234"), 0, 17)))
+            Some(Ok(Chunk::new(String::from("#1234"), 0, 17, 17)))
         );
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("#18"), 0, 8))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("DEO"), 0, 12))));
+        assert_eq!(pp.next(), Some(Ok(expanded("#18", 0, 8, 8, &emit))));
+        assert_eq!(pp.next(), Some(Ok(expanded("DEO", 0, 12, 12, &emit))));
         assert_eq!(pp.next(), None);
     }
 
@@ -141,25 +306,176 @@ mod tests {
     fn macros_inside_macros_work() {
         let mut buffer =
             Cursor::new("%EMIT { #18 DEO } %TEST-SHORT { EQU2 #30 ADD EMIT } TEST-SHORT");
-        let mut source = Chunker::new(&mut buffer);
-        let mut pp = PreProcessMacros::new(&mut source);
-
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("EQU2"), 0, 32))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("#30"), 0, 37))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("ADD"), 0, 41))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("#18"), 0, 8))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("DEO"), 0, 12))));
+        let mut source = Chunker::new(PathBuf::new(), &mut buffer);
+        let mut pp = PreProcessMacros::new(PathBuf::new(), &mut source);
+        let test_short = Chunk::new(String::from("TEST-SHORT"), 0, 52, 52);
+        let emit = expanded("EMIT", 0, 45, 45, &test_short);
+
+        assert_eq!(
+            pp.next(),
+            Some(Ok(expanded("EQU2", 0, 32, 32, &test_short)))
+        );
+        assert_eq!(pp.next(), Some(Ok(expanded("#30", 0, 37, 37, &test_short))));
+        assert_eq!(pp.next(), Some(Ok(expanded("ADD", 0, 41, 41, &test_short))));
+        assert_eq!(pp.next(), Some(Ok(expanded("#18", 0, 8, 8, &emit))));
+        assert_eq!(pp.next(), Some(Ok(expanded("DEO", 0, 12, 12, &emit))));
         assert_eq!(pp.next(), None);
     }
 
     #[test]
     fn macros_inside_macros_work2() {
         let mut buffer = Cursor::new("%FOO { 13 } %BAR { FOO FOO } BAR");
-        let mut source = Chunker::new(&mut buffer);
-        let mut pp = PreProcessMacros::new(&mut source);
+        let mut source = Chunker::new(PathBuf::new(), &mut buffer);
+        let mut pp = PreProcessMacros::new(PathBuf::new(), &mut source);
+        let bar = Chunk::new(String::from("BAR"), 0, 29, 29);
+
+        let first = expanded("FOO", 0, 19, 19, &bar);
+        let second = expanded("FOO", 0, 23, 23, &bar);
+        assert_eq!(pp.next(), Some(Ok(expanded("13", 0, 7, 7, &first))));
+        assert_eq!(pp.next(), Some(Ok(expanded("13", 0, 7, 7, &second))));
+        assert_eq!(pp.next(), None);
+    }
+
+    #[test]
+    fn lambdas_inside_macros_work() {
+        let mut buffer = Cursor::new("%FOO { ?{ { 13 } } } FOO");
+        let mut source = Chunker::new(PathBuf::new(), &mut buffer);
+        let mut pp = PreProcessMacros::new(PathBuf::new(), &mut source);
+        let foo = Chunk::new(String::from("FOO"), 0, 21, 21);
+
+        assert_eq!(pp.next(), Some(Ok(expanded("?{", 0, 7, 7, &foo))));
+        assert_eq!(pp.next(), Some(Ok(expanded("{", 0, 10, 10, &foo))));
+        assert_eq!(pp.next(), Some(Ok(expanded("13", 0, 12, 12, &foo))));
+        assert_eq!(pp.next(), Some(Ok(expanded("}", 0, 15, 15, &foo))));
+        assert_eq!(pp.next(), Some(Ok(expanded("}", 0, 17, 17, &foo))));
+        assert_eq!(pp.next(), None);
+    }
+
+    fn errors(source: &str) -> Vec<String> {
+        let mut buffer = Cursor::new(source);
+        let mut source = Chunker::new(PathBuf::new(), &mut buffer);
+        let pp = PreProcessMacros::new(PathBuf::from("main.tal"), &mut source);
+        pp.filter_map(|next| next.err())
+            .map(|err| err.message().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn macros_without_a_body_fail() {
+        let mut buffer = Cursor::new("%EMIT #18 DEO");
+        let mut source = Chunker::new(PathBuf::new(), &mut buffer);
+        let mut pp = PreProcessMacros::new(PathBuf::from("main.tal"), &mut source);
+
+        assert_eq!(
+            pp.next(),
+            Some(Err(Error::new(
+                "macro \"EMIT\" must be followed by { to open its body".to_string(),
+                Chunk::new(String::from("%EMIT"), 0, 0, 0),
+                PathBuf::from("main.tal"),
+            )
+            .with_code("E0005")))
+        );
+        assert_eq!(
+            pp.next(),
+            Some(Ok(Chunk::new(String::from("#18"), 0, 6, 6)))
+        );
+        assert_eq!(
+            pp.next(),
+            Some(Ok(Chunk::new(String::from("DEO"), 0, 10, 10)))
+        );
+        assert_eq!(pp.next(), None);
+
+        assert_eq!(
+            errors("#18 %EMIT"),
+            vec!["reached EOF without finding the { for macro \"EMIT\""]
+        );
+    }
+
+    #[test]
+    fn unterminated_macros_fail() {
+        let mut buffer = Cursor::new("%EMIT { #18 DEO");
+        let mut source = Chunker::new(PathBuf::new(), &mut buffer);
+        let mut pp = PreProcessMacros::new(PathBuf::from("main.tal"), &mut source);
+
+        assert_eq!(
+            pp.next(),
+            Some(Err(Error::new(
+                "reached EOF without finding macro close for \"EMIT\"".to_string(),
+                Chunk::new(String::from("%EMIT"), 0, 0, 0),
+                PathBuf::from("main.tal"),
+            )
+            .with_code("E0005")))
+        );
+        assert_eq!(pp.next(), None);
+    }
+
+    #[test]
+    fn redefined_macros_fail() {
+        let mut buffer = Cursor::new("%EMIT { #18 DEO }\n%EMIT { #19 DEO } EMIT");
+        let mut source = Chunker::new(PathBuf::new(), &mut buffer);
+        let mut pp = PreProcessMacros::new(PathBuf::from("main.tal"), &mut source);
+        let emit = Chunk::new(String::from("EMIT"), 1, 18, 36);
 
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("13"), 0, 7))));
-        assert_eq!(pp.next(), Some(Ok(Chunk::new(String::from("13"), 0, 7))));
+        assert_eq!(
+            pp.next(),
+            Some(Err(Error::new(
+                "macro \"EMIT\" is already defined".to_string(),
+                Chunk::new(String::from("%EMIT"), 1, 0, 18),
+                PathBuf::from("main.tal"),
+            )
+            .with_code("E0006")
+            .with_label(
+                "first defined here".to_string(),
+                Chunk::new(String::from("%EMIT"), 0, 0, 0),
+                PathBuf::from("main.tal"),
+            )))
+        );
+        // The first definition is kept
+        assert_eq!(pp.next(), Some(Ok(expanded("#18", 0, 8, 8, &emit))));
+        assert_eq!(pp.next(), Some(Ok(expanded("DEO", 0, 12, 12, &emit))));
         assert_eq!(pp.next(), None);
     }
+
+    #[test]
+    fn recursive_macros_fail() {
+        assert_eq!(
+            errors("%LOOP { #01 LOOP } LOOP"),
+            vec!["macro \"LOOP\" invokes itself: LOOP at line 1 column 20 -> LOOP at line 1 column 13"]
+        );
+        assert_eq!(
+            errors("%PING { PONG } %PONG { PING PING } PING"),
+            vec![
+                "macro \"PING\" invokes itself: PING at line 1 column 36 -> PONG at line 1 column 9 -> PING at line 1 column 24",
+                "macro \"PING\" invokes itself: PING at line 1 column 36 -> PONG at line 1 column 9 -> PING at line 1 column 29",
+            ]
+        );
+    }
+
+    #[test]
+    fn deeply_nested_macros_fail() {
+        let mut source = String::from("%M0 { #01 }");
+        for i in 1..=MAX_MACRO_DEPTH {
+            source.push_str(&format!(" %M{i} {{ M{} }}", i - 1));
+        }
+        source.push_str(&format!(" M{}", MAX_MACRO_DEPTH - 1));
+        assert_eq!(errors(&source), Vec::<String>::new());
+
+        source.push_str(&format!(" M{MAX_MACRO_DEPTH}"));
+        let errors = errors(&source);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("macros are nested more than 64 deep: M64 at"));
+    }
+
+    #[test]
+    fn macro_names_that_mean_something_else_fail() {
+        assert_eq!(
+            errors("%ADD2k { #01 } %cafe { #02 } %ff { #03 } % { #04 }"),
+            vec![
+                "macro name \"ADD2k\" is an opcode",
+                "macro name \"cafe\" is a hex literal",
+                "macro name \"ff\" is a hex literal",
+                "macro name is empty",
+            ]
+        );
+    }
 }
@@ -0,0 +1,109 @@
+use crate::chunker::Chunk;
+use crate::json::quote;
+use crate::listing::Listing;
+use std::io::Write;
+use std::path::Path;
+
+fn position(chunk: &Chunk, file: &Path) -> String {
+    format!(
+        "\"file\": {}, \"line\": {}, \"column\": {}",
+        quote(&chunk.file(file).display().to_string()),
+        chunk.line + 1,
+        chunk.column + 1
+    )
+}
+
+// The macro invocations a chunk was expanded from, innermost first
+fn expansion(chunk: &Chunk, file: &Path) -> String {
+    let mut frames = vec![];
+    let mut expansion = &chunk.expansion;
+    while let Some(frame) = expansion {
+        frames.push(format!(
+            "{{\"macro\": {}, {}}}",
+            quote(&frame.name),
+            position(&frame.chunk, file)
+        ));
+        expansion = &frame.chunk.expansion;
+    }
+    format!("[{}]", frames.join(", "))
+}
+
+// A JSON object with a range for every token that wrote bytes: the addresses it covers (end is
+// exclusive), the file, line and column it came from, the parent label it was assembled under and
+// the macro invocations it was expanded from, innermost first
+pub fn write_source_map(
+    listing: &Listing,
+    file: &Path,
+    writer: &mut dyn Write,
+) -> std::io::Result<()> {
+    let ranges: Vec<String> = listing
+        .iter()
+        .filter(|entry| entry.length > 0)
+        .map(|entry| {
+            let parent = match &entry.parent {
+                Some(parent) => quote(parent),
+                None => "null".to_string(),
+            };
+            format!(
+                "{{\"start\": {}, \"end\": {}, {}, \"token\": {}, \"parent\": {}, \"expansion\": {}}}",
+                entry.address,
+                entry.address as usize + entry.length as usize,
+                position(&entry.chunk, file),
+                quote(&entry.chunk.value),
+                parent,
+                expansion(&entry.chunk, file)
+            )
+        })
+        .collect();
+
+    writeln!(writer, "{{")?;
+    writeln!(
+        writer,
+        "  \"file\": {},",
+        quote(&file.display().to_string())
+    )?;
+    writeln!(writer, "  \"ranges\": [")?;
+    for (i, range) in ranges.iter().enumerate() {
+        let separator = if i + 1 < ranges.len() { "," } else { "" };
+        writeln!(writer, "    {range}{separator}")?;
+    }
+    writeln!(writer, "  ]")?;
+    writeln!(writer, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::Chunker;
+    use crate::include_resolver::MemoryResolver;
+    use crate::parser::parse_chunks;
+    use crate::parser::ParseOptions;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    #[test]
+    fn it_works() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("lib/a.tal"), "@lib\n  #01".to_string());
+        let source = "%EMIT { #18 DEO }\n|0100 @on-reset ~lib/a.tal\n&loop EMIT";
+        let mut reader = Cursor::new(source);
+        let mut chunker = Chunker::new(PathBuf::new(), &mut reader);
+        let file = PathBuf::from("main.tal");
+        let options = ParseOptions::default();
+        let assembly = parse_chunks(&resolver, file.clone(), &options, &mut chunker).unwrap();
+
+        let mut output = vec![];
+        write_source_map(&assembly.listing, &file, &mut output).unwrap();
+
+        let expected = r##"{
+  "file": "main.tal",
+  "ranges": [
+    {"start": 256, "end": 258, "file": "lib/a.tal", "line": 2, "column": 3, "token": "#01", "parent": "lib", "expansion": []},
+    {"start": 258, "end": 260, "file": "main.tal", "line": 1, "column": 9, "token": "#18", "parent": "lib", "expansion": [{"macro": "EMIT", "file": "main.tal", "line": 3, "column": 7}]},
+    {"start": 260, "end": 261, "file": "main.tal", "line": 1, "column": 13, "token": "DEO", "parent": "lib", "expansion": [{"macro": "EMIT", "file": "main.tal", "line": 3, "column": 7}]}
+  ]
+}
+"##;
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+}
@@ -0,0 +1,1004 @@
+use crate::chunker::Chunk;
+use crate::error::Error;
+use crate::include_resolver::IncludeResolver;
+use crate::lexer::lex;
+use crate::lexer::Lexeme;
+use crate::lexer::LexemeKind;
+use crate::opcode::Opcode;
+use crate::parser::Assembly;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::path::Path;
+use std::path::PathBuf;
+
+// ROMs are loaded into memory at this address
+const ROM_START: usize = 0x100;
+// Instructions simulated per routine before giving up, loops that grow the stack never end
+const MAX_STEPS: usize = 100_000;
+
+// What a routine does to the working stack, in bytes: how far down it reaches, and how many bytes
+// it leaves in their place
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Effect {
+    pub takes: usize,
+    pub gives: usize,
+}
+
+// A stack effect comment like ( a b* -- c ), where names ending in * are shorts
+#[derive(Debug, PartialEq)]
+pub struct Signature {
+    pub effect: Effect,
+    pub text: String,
+}
+
+// Vectors are written ( -> ) and don't return, and comments without -- aren't signatures.
+// Neither are comments describing alternatives with OR, or with any word that isn't a name, ? or --
+pub fn parse_signature(comment: &str) -> Option<Signature> {
+    let words: Vec<&str> = comment.split_whitespace().collect();
+    let words = words.strip_prefix(&["("])?.strip_suffix(&[")"])?;
+    if words.contains(&"->") || words.contains(&"OR") {
+        return None;
+    }
+    let is_name = |word: &&str| {
+        let mut chars = word.chars();
+        chars.all(|c| c.is_alphanumeric() || "-_*^/.".contains(c))
+    };
+    if !words.iter().all(|word| is_name(word) || *word == "?") {
+        return None;
+    }
+    let separator = words.iter().position(|word| *word == "--")?;
+    let (before, after) = (&words[..separator], &words[separator + 1..]);
+    if after.contains(&"--") {
+        return None;
+    }
+    let size = |words: &[&str]| -> usize {
+        let sizes = words
+            .iter()
+            .map(|word| if word.ends_with('*') { 2 } else { 1 });
+        sizes.sum()
+    };
+    Some(Signature {
+        effect: Effect {
+            takes: size(before),
+            gives: size(after),
+        },
+        text: words.join(" "),
+    })
+}
+
+// What's known about a byte on a stack
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Value {
+    // Put there by a LIT
+    Byte(u8),
+    // The result of a comparison, which is 0 or 1. Jumping by one skips an instruction
+    Flag,
+    Unknown,
+}
+
+// Bytes put on a stack since the routine started, and how many it took from below that
+#[derive(Debug, Clone, Default)]
+struct Stack {
+    bytes: Vec<Value>,
+    taken: usize,
+}
+
+impl Stack {
+    fn push(&mut self, value: Value) {
+        self.bytes.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        match self.bytes.pop() {
+            Some(value) => value,
+            None => {
+                self.taken += 1;
+                Value::Unknown
+            }
+        }
+    }
+
+    fn pop_short(&mut self) -> Option<u16> {
+        match (self.pop(), self.pop()) {
+            (Value::Byte(low), Value::Byte(high)) => Some(u16::from_be_bytes([high, low])),
+            _ => None,
+        }
+    }
+
+    // Compared to where it started
+    fn depth(&self) -> isize {
+        self.bytes.len() as isize - self.taken as isize
+    }
+
+    fn effect(&self) -> Effect {
+        Effect {
+            takes: self.taken,
+            gives: self.bytes.len(),
+        }
+    }
+}
+
+// Where a path through a routine ends up
+#[derive(Debug, PartialEq)]
+pub enum Exit {
+    // Returned to the caller. returns is how many bytes were left on the return stack above the
+    // caller's address, or taken from below it when negative
+    Return {
+        address: u16,
+        effect: Effect,
+        returns: isize,
+    },
+    // Stopped at a BRK, as vectors do
+    Halt,
+    // Somewhere the effect can't be followed: a jump to an address that isn't known, or a call to
+    // a routine whose effect isn't known
+    Unknown,
+}
+
+#[derive(Clone)]
+struct State {
+    address: u16,
+    working: Stack,
+    returns: Stack,
+}
+
+// Follows every path through the routine at start, which ends where end starts. Jumps forward
+// and back within it are followed, and jumps anywhere else are tail calls. callee gives the
+// effect of a routine that's called
+pub fn simulate(
+    rom: &[u8],
+    start: u16,
+    end: u16,
+    callee: &dyn Fn(u16) -> Option<Effect>,
+) -> Vec<Exit> {
+    let read = |address: u16| -> Option<u8> {
+        let index = (address as usize).checked_sub(ROM_START)?;
+        rom.get(index).copied()
+    };
+
+    let mut exits = vec![];
+    let mut seen = HashSet::new();
+    let mut states = vec![State {
+        address: start,
+        working: Stack::default(),
+        returns: Stack::default(),
+    }];
+    let mut steps = 0;
+
+    while let Some(mut state) = states.pop() {
+        loop {
+            steps += 1;
+            if steps > MAX_STEPS {
+                exits.push(Exit::Unknown);
+                return exits;
+            }
+            // Coming back around a loop the same way adds nothing. Paths that meet with the same
+            // depth can still have reached different distances down the stacks, so both count
+            let key = (
+                state.address,
+                state.working.depth(),
+                state.working.taken,
+                state.returns.depth(),
+                state.returns.taken,
+            );
+            if !seen.insert(key) {
+                break;
+            }
+
+            let address = state.address;
+            let byte = match read(address) {
+                Some(byte) => byte,
+                None => {
+                    exits.push(Exit::Unknown);
+                    break;
+                }
+            };
+            let opcode = Opcode::from_byte(byte);
+            let immediate = match (read(address.wrapping_add(1)), read(address.wrapping_add(2))) {
+                (Some(high), Some(low)) => {
+                    let offset = i16::from_be_bytes([high, low]);
+                    Some(address.wrapping_add(3).wrapping_add(offset as u16))
+                }
+                _ => None,
+            };
+
+            // Where the path goes next, None when it's over
+            let next = match opcode {
+                Opcode::BRK => {
+                    exits.push(Exit::Halt);
+                    None
+                }
+                Opcode::LIT(two, return_stack) => {
+                    let stack = if return_stack {
+                        &mut state.returns
+                    } else {
+                        &mut state.working
+                    };
+                    let length = if two { 2 } else { 1 };
+                    for i in 1..=length {
+                        let byte = read(address.wrapping_add(i));
+                        stack.push(byte.map_or(Value::Unknown, Value::Byte));
+                    }
+                    Some(address.wrapping_add(1 + length))
+                }
+                Opcode::JCI | Opcode::JMI | Opcode::JSI => {
+                    let after = address.wrapping_add(3);
+                    match (opcode, immediate) {
+                        (_, None) => {
+                            exits.push(Exit::Unknown);
+                            None
+                        }
+                        (Opcode::JCI, Some(target)) => {
+                            state.working.pop();
+                            states.push(State {
+                                address: target,
+                                ..state.clone()
+                            });
+                            Some(after)
+                        }
+                        (Opcode::JMI, Some(target)) => {
+                            jump(&mut state, target, start, end, callee, &mut exits)
+                        }
+                        (_, Some(target)) => call(&mut state, target, callee, &mut exits, after),
+                    }
+                }
+                Opcode::JMP(two, false, true) => {
+                    // The usual way back is JMP2r with the caller's address, which isn't known
+                    let target = if two { state.returns.pop_short() } else { None };
+                    match (two, target) {
+                        (true, None) => {
+                            exits.push(Exit::Return {
+                                address,
+                                effect: state.working.effect(),
+                                returns: state.returns.depth() + 2,
+                            });
+                            None
+                        }
+                        _ => {
+                            exits.push(Exit::Unknown);
+                            None
+                        }
+                    }
+                }
+                Opcode::JMP(two, keep, false)
+                | Opcode::JCN(two, keep, false)
+                | Opcode::JSR(two, keep, false) => {
+                    let after = address.wrapping_add(1);
+                    let target = if two {
+                        state.working.pop_short()
+                    } else {
+                        match state.working.pop() {
+                            Value::Byte(offset) => Some(after.wrapping_add(offset as i8 as u16)),
+                            // A flag JMP skips the next instruction when it's set, the same as
+                            // JCN to one past it
+                            Value::Flag if !keep && matches!(opcode, Opcode::JMP(..)) => {
+                                let skip = Opcode::from_byte(read(after).unwrap_or(0));
+                                let length = match skip {
+                                    Opcode::LIT(true, _) => 3,
+                                    Opcode::LIT(false, _) => 2,
+                                    Opcode::JCI | Opcode::JMI | Opcode::JSI => 3,
+                                    _ => 1,
+                                };
+                                states.push(State {
+                                    address: after.wrapping_add(length),
+                                    ..state.clone()
+                                });
+                                Some(after)
+                            }
+                            _ => None,
+                        }
+                    };
+                    if let Opcode::JCN(_, _, _) = opcode {
+                        state.working.pop();
+                    }
+                    match target {
+                        // The address is still there to be used again, so it's not known what
+                        // the path does with it
+                        _ if keep => {
+                            exits.push(Exit::Unknown);
+                            None
+                        }
+                        None => {
+                            exits.push(Exit::Unknown);
+                            None
+                        }
+                        Some(target) => match opcode {
+                            Opcode::JMP(_, _, _) => {
+                                jump(&mut state, target, start, end, callee, &mut exits)
+                            }
+                            Opcode::JCN(_, _, _) => {
+                                states.push(State {
+                                    address: target,
+                                    ..state.clone()
+                                });
+                                Some(after)
+                            }
+                            _ => call(&mut state, target, callee, &mut exits, after),
+                        },
+                    }
+                }
+                // Other jumps from the return stack are too unusual to follow
+                Opcode::JMP(_, _, true) | Opcode::JCN(_, _, true) | Opcode::JSR(_, _, true) => {
+                    exits.push(Exit::Unknown);
+                    None
+                }
+                _ => {
+                    let effect = opcode.stack_effect();
+                    let (own, other) = if effect.return_stack {
+                        (&mut state.returns, &mut state.working)
+                    } else {
+                        (&mut state.working, &mut state.returns)
+                    };
+                    let mut taken: Vec<Value> = (0..effect.takes).map(|_| own.pop()).collect();
+                    if effect.keep {
+                        taken.reverse();
+                        for value in taken {
+                            own.push(value);
+                        }
+                    }
+                    let given = match opcode {
+                        Opcode::EQU(..) | Opcode::NEQ(..) | Opcode::GTH(..) | Opcode::LTH(..) => {
+                            Value::Flag
+                        }
+                        _ => Value::Unknown,
+                    };
+                    for _ in 0..effect.gives {
+                        own.push(given);
+                    }
+                    for _ in 0..effect.other {
+                        other.push(Value::Unknown);
+                    }
+                    Some(address.wrapping_add(1))
+                }
+            };
+
+            match next {
+                Some(next) => state.address = next,
+                None => break,
+            }
+        }
+    }
+    exits
+}
+
+// Applies the effect of calling target, and carries on after the call if it's known
+fn call(
+    state: &mut State,
+    target: u16,
+    callee: &dyn Fn(u16) -> Option<Effect>,
+    exits: &mut Vec<Exit>,
+    after: u16,
+) -> Option<u16> {
+    match callee(target) {
+        Some(effect) => {
+            for _ in 0..effect.takes {
+                state.working.pop();
+            }
+            for _ in 0..effect.gives {
+                state.working.push(Value::Unknown);
+            }
+            Some(after)
+        }
+        None => {
+            exits.push(Exit::Unknown);
+            None
+        }
+    }
+}
+
+// Jumps within the routine carry on from the target. Anywhere else is a tail call, which returns
+// to this routine's caller
+fn jump(
+    state: &mut State,
+    target: u16,
+    start: u16,
+    end: u16,
+    callee: &dyn Fn(u16) -> Option<Effect>,
+    exits: &mut Vec<Exit>,
+) -> Option<u16> {
+    if (start..end).contains(&target) {
+        return Some(target);
+    }
+    let address = state.address;
+    call(state, target, callee, exits, address)?;
+    exits.push(Exit::Return {
+        address,
+        effect: state.working.effect(),
+        returns: state.returns.depth(),
+    });
+    None
+}
+
+// A routine defined with an @label, and where it ends
+pub struct Routine {
+    pub name: String,
+    pub start: u16,
+    pub end: u16,
+    pub chunk: Chunk,
+    pub file: PathBuf,
+    pub signature: Option<Signature>,
+}
+
+// The comment straight after the label at chunk, if there is one
+fn comment_after<'a>(lexemes: &'a [Lexeme], chunk: &Chunk) -> Option<&'a str> {
+    let index = lexemes
+        .iter()
+        .position(|lexeme| lexeme.chunk.offset == chunk.offset)?;
+    lexemes[index + 1..]
+        .iter()
+        .find(|lexeme| !matches!(lexeme.kind, LexemeKind::Space | LexemeKind::Newline))
+        .filter(|lexeme| lexeme.kind == LexemeKind::Comment)
+        .map(|lexeme| lexeme.value())
+}
+
+// Every @label in the ROM with the comment after it, read from source for the file being
+// assembled and through resolver for included ones. A routine ends where the next one starts
+pub fn find_routines(
+    assembly: &Assembly,
+    source: &str,
+    file: &Path,
+    resolver: &dyn IncludeResolver,
+) -> Vec<Routine> {
+    let end = (ROM_START + assembly.rom.get_written_bytes().len()).min(0xffff) as u16;
+    let mut lexemes: HashMap<PathBuf, Vec<Lexeme>> = HashMap::new();
+    let mut routines: Vec<Routine> = vec![];
+    for entry in assembly.listing.iter() {
+        let name = match &entry.label {
+            // Lambdas are labelled too, but they're part of the routine around them
+            Some(name) if !name.contains('/') && !name.starts_with('λ') => name,
+            _ => continue,
+        };
+        if (entry.address as usize) < ROM_START || entry.chunk.expansion.is_some() {
+            continue;
+        }
+
+        let chunk_file = entry.chunk.file(file).to_path_buf();
+        let file_lexemes = lexemes.entry(chunk_file.clone()).or_insert_with(|| {
+            let text = if chunk_file == file {
+                Ok(source.to_string())
+            } else {
+                let bytes = resolver.resolve(&chunk_file);
+                bytes.and_then(|bytes| String::from_utf8(bytes).map_err(|err| err.to_string()))
+            };
+            let lexed = text.map(|text| lex(&text, chunk_file.clone()));
+            lexed.unwrap_or(Ok(vec![])).unwrap_or_default()
+        });
+        let signature = comment_after(file_lexemes, &entry.chunk).and_then(parse_signature);
+
+        routines.push(Routine {
+            name: name.clone(),
+            start: entry.address,
+            end,
+            chunk: entry.chunk.clone(),
+            file: chunk_file,
+            signature,
+        });
+    }
+
+    routines.sort_by_key(|routine| routine.start);
+    for i in 1..routines.len() {
+        let start = routines[i].start;
+        routines[i - 1].end = start;
+    }
+    routines
+}
+
+fn plural_bytes(count: usize) -> String {
+    match count {
+        1 => "1 byte".to_string(),
+        count => format!("{count} bytes"),
+    }
+}
+
+// The chunk that assembled to address, for pointing at where a routine returns
+fn chunk_at(assembly: &Assembly, address: u16) -> Option<&Chunk> {
+    assembly
+        .listing
+        .iter()
+        .filter(|entry| entry.length > 0)
+        .find(|entry| (entry.address..entry.address + entry.length).contains(&address))
+        .map(|entry| &entry.chunk)
+}
+
+// Warns about routines with a signature that their bodies don't agree with. Calls to routines
+// with signatures are taken at their word, and paths through anything else aren't checked
+pub fn check_stack_effects(
+    assembly: &Assembly,
+    source: &str,
+    file: &Path,
+    resolver: &dyn IncludeResolver,
+) -> Vec<Error> {
+    let routines = find_routines(assembly, source, file, resolver);
+    let effects: HashMap<u16, Effect> = routines
+        .iter()
+        .filter_map(|routine| Some((routine.start, routine.signature.as_ref()?.effect)))
+        .collect();
+    let callee = |address: u16| effects.get(&address).copied();
+    let rom = assembly.rom.get_written_bytes();
+
+    let mut warnings = vec![];
+    for routine in &routines {
+        let signature = match &routine.signature {
+            Some(signature) => signature,
+            None => continue,
+        };
+        let declared = signature.effect;
+        let exits = simulate(rom, routine.start, routine.end, &callee);
+
+        let problem = exits.iter().find_map(|exit| match exit {
+            Exit::Return {
+                address, returns, ..
+            } if *returns > 0 => Some((
+                *address,
+                format!(
+                    "routine \"{}\" returns with {} left on the return stack",
+                    routine.name,
+                    plural_bytes(*returns as usize)
+                ),
+                "take everything put on the return stack off it before returning".to_string(),
+            )),
+            Exit::Return {
+                address, returns, ..
+            } if *returns < 0 => Some((
+                *address,
+                format!(
+                    "routine \"{}\" takes {} from the return stack that it didn't put there",
+                    routine.name,
+                    plural_bytes(-returns as usize)
+                ),
+                "only take from the return stack what was put there".to_string(),
+            )),
+            Exit::Return {
+                address, effect, ..
+            } if effect.takes > declared.takes => Some((
+                *address,
+                format!(
+                    "routine \"{}\" takes {} from the working stack, but its signature ( {} ) takes {}",
+                    routine.name,
+                    plural_bytes(effect.takes),
+                    signature.text,
+                    plural_bytes(declared.takes)
+                ),
+                "take only what the signature declares, or fix the signature".to_string(),
+            )),
+            Exit::Return {
+                address, effect, ..
+            } => {
+                let leaves = declared.takes - effect.takes + effect.gives;
+                if leaves == declared.gives {
+                    return None;
+                }
+                let help = if leaves > declared.gives {
+                    format!(
+                        "pop the extra {} before returning, or fix the signature",
+                        plural_bytes(leaves - declared.gives)
+                    )
+                } else {
+                    "leave everything the signature declares, or fix the signature".to_string()
+                };
+                Some((
+                    *address,
+                    format!(
+                        "routine \"{}\" leaves {} on the working stack, but its signature ( {} ) leaves {}",
+                        routine.name,
+                        plural_bytes(leaves),
+                        signature.text,
+                        plural_bytes(declared.gives)
+                    ),
+                    help,
+                ))
+            }
+            Exit::Halt | Exit::Unknown => None,
+        });
+
+        if let Some((address, message, help)) = problem {
+            let chunk = chunk_at(assembly, address)
+                .unwrap_or(&routine.chunk)
+                .clone();
+            let chunk_file = chunk.file(file).to_path_buf();
+            let warning = Error::warning(message, chunk, chunk_file)
+                .with_code("W0002")
+                .with_label(
+                    "signature declared here".to_string(),
+                    routine.chunk.clone(),
+                    routine.file.clone(),
+                )
+                .with_help(help);
+            warnings.push(warning);
+        }
+    }
+    warnings
+}
+
+// What a routine was found to do to the stacks
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Inferred {
+    // Every path returns having done the same thing, with returns as in Exit::Return
+    Effect { working: Effect, returns: isize },
+    // Paths return having left different numbers of bytes
+    Mismatched,
+    // Every path ends at a BRK
+    Halts,
+    Unknown,
+}
+
+impl Display for Inferred {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Inferred::Effect { working, returns } => {
+                write!(f, "( {} -- {} )", working.takes, working.gives)?;
+                match returns {
+                    0 => Ok(()),
+                    returns if *returns > 0 => {
+                        write!(f, " leaving {returns} on the return stack")
+                    }
+                    returns => write!(f, " taking {} from the return stack", -returns),
+                }
+            }
+            Inferred::Mismatched => write!(f, "unknown, paths return with different depths"),
+            Inferred::Halts => write!(f, "doesn't return"),
+            Inferred::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+// Paths that reach further down the stack are counted as giving back what the others leave
+// alone, so ( a b -- ) and ( b -- ) agree. Paths that end at a BRK don't return to the caller,
+// so only matter when none do
+fn summarize(exits: &[Exit]) -> Inferred {
+    let returns: Vec<(Effect, isize)> = exits
+        .iter()
+        .filter_map(|exit| match exit {
+            Exit::Return {
+                effect, returns, ..
+            } => Some((*effect, *returns)),
+            _ => None,
+        })
+        .collect();
+    let takes = returns.iter().map(|(effect, _)| effect.takes).max();
+    let takes = takes.unwrap_or(0);
+    let mut effects = returns.iter().map(|(effect, returns)| {
+        let gives = effect.gives + takes - effect.takes;
+        (Effect { takes, gives }, *returns)
+    });
+
+    match effects.next() {
+        Some(first) if effects.any(|other| other != first) => Inferred::Mismatched,
+        _ if exits.contains(&Exit::Unknown) => Inferred::Unknown,
+        Some((working, returns)) => Inferred::Effect { working, returns },
+        None if exits.contains(&Exit::Halt) => Inferred::Halts,
+        None => Inferred::Unknown,
+    }
+}
+
+// Works out what every routine does to the stacks, without needing signatures. Calls to
+// routines and their children use what's been worked out for them, so the effects are worked
+// out again until no more become known. Recursive routines stay unknown
+pub fn infer_stack_effects(
+    assembly: &Assembly,
+    source: &str,
+    file: &Path,
+    resolver: &dyn IncludeResolver,
+) -> Vec<(Routine, Inferred)> {
+    let routines = find_routines(assembly, source, file, resolver);
+    let rom = assembly.rom.get_written_bytes();
+
+    // Children run to the end of the routine they're in
+    let mut entries = BTreeMap::new();
+    for entry in assembly.listing.iter() {
+        if entry.label.is_none() {
+            continue;
+        }
+        let routine = routines
+            .iter()
+            .find(|routine| (routine.start..routine.end).contains(&entry.address));
+        if let Some(routine) = routine {
+            entries.insert(entry.address, routine.end);
+        }
+    }
+
+    let count_known = |inferred: &HashMap<u16, Inferred>| {
+        let known = inferred.values();
+        known
+            .filter(|inferred| matches!(inferred, Inferred::Effect { returns: 0, .. }))
+            .count()
+    };
+    let mut inferred: HashMap<u16, Inferred> = HashMap::new();
+    loop {
+        let callee = |address: u16| match inferred.get(&address) {
+            Some(Inferred::Effect {
+                working,
+                returns: 0,
+            }) => Some(*working),
+            _ => None,
+        };
+        let next: HashMap<u16, Inferred> = entries
+            .iter()
+            .map(|(&start, &end)| (start, summarize(&simulate(rom, start, end, &callee))))
+            .collect();
+        let settled = count_known(&next) == count_known(&inferred);
+        inferred = next;
+        if settled {
+            break;
+        }
+    }
+
+    routines
+        .into_iter()
+        .map(|routine| {
+            let effect = inferred.get(&routine.start).copied();
+            (routine, effect.unwrap_or(Inferred::Unknown))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble_with_resolver;
+    use crate::include_resolver::MemoryResolver;
+    use crate::Options;
+
+    fn check(source: &str) -> Vec<Error> {
+        let options = Options {
+            file: PathBuf::from("test.tal"),
+            check_stack_effects: true,
+            ..Options::default()
+        };
+        let assembly = assemble_with_resolver(source, &options, &MemoryResolver::new()).unwrap();
+        assembly.warnings
+    }
+
+    fn effect(takes: usize, gives: usize) -> Effect {
+        Effect { takes, gives }
+    }
+
+    #[test]
+    fn it_parses_signatures() {
+        let signature = parse_signature("( a b* -- c )").unwrap();
+        assert_eq!(signature.effect, effect(3, 1));
+        assert_eq!(signature.text, "a b* -- c");
+        assert_eq!(parse_signature("( -- )").unwrap().effect, effect(0, 0));
+        assert_eq!(parse_signature("( -> )"), None);
+        assert_eq!(parse_signature("( draws the screen )"), None);
+        assert_eq!(parse_signature("( a -- b -- c )"), None);
+        assert_eq!(
+            parse_signature("( flag -- ? )").unwrap().effect,
+            effect(1, 1)
+        );
+    }
+
+    #[test]
+    fn it_ignores_prose_comments() {
+        let strcmp = "( a* b* -- a-end* b-end* order nonzero if strings differ
+                OR a-end* b-end* 00    00      if strings match )";
+        assert_eq!(parse_signature(strcmp), None);
+        let prose = "( x -- y, which is x + 1; or x - 1 if it's odd )";
+        assert_eq!(parse_signature(prose), None);
+        assert_eq!(parse_signature("( x -- y (doubled) )"), None);
+        assert_eq!(parse_signature("( addr* -- value, or nothing )"), None);
+    }
+
+    #[test]
+    fn it_simulates_straight_line_code() {
+        // #12 ADD JMP2r
+        let rom = [0x80, 0x12, 0x18, 0x6c];
+        let exits = simulate(&rom, 0x100, 0x104, &|_| None);
+        let expected = Exit::Return {
+            address: 0x103,
+            effect: effect(1, 1),
+            returns: 0,
+        };
+        assert_eq!(exits, [expected]);
+    }
+
+    #[test]
+    fn it_follows_both_sides_of_branches() {
+        // Only the path that doesn't jump leaves a byte behind
+        let warnings = check("|0100 @main ( a* -- ) #00 ?{ POP2 JMP2r } POP JMP2r");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].message(),
+            "routine \"main\" leaves 1 byte on the working stack, but its signature ( a* -- ) leaves 0 bytes"
+        );
+    }
+
+    #[test]
+    fn it_accepts_matching_bodies() {
+        let source = "|0100 @main ( -- ) #0102 add BRK
+@add ( a* b -- c* ) #00 SWP ADD2 JMP2r";
+        let warnings = check(source);
+        assert_eq!(warnings, []);
+    }
+
+    #[test]
+    fn it_warns_about_missing_pops() {
+        let source = "|0100 @print ( str* -- )\n\tLDAk #18 DEO\n\tJMP2r";
+        let warnings = check(source);
+        assert_eq!(warnings.len(), 1);
+        let warning = &warnings[0];
+        assert_eq!(warning.severity(), crate::Severity::Warning);
+        assert_eq!(warning.code(), Some("W0002"));
+        assert_eq!(
+            warning.message(),
+            "routine \"print\" leaves 2 bytes on the working stack, but its signature ( str* -- ) leaves 0 bytes"
+        );
+        assert_eq!(warning.chunk().value, "JMP2r");
+        assert_eq!(warning.labels()[0].chunk.value, "@print");
+        assert_eq!(
+            warning.help(),
+            ["pop the extra 2 bytes before returning, or fix the signature"]
+        );
+    }
+
+    #[test]
+    fn it_warns_about_taking_too_much() {
+        let warnings = check("|0100 @add ( a b -- c ) ADD ADD JMP2r");
+        assert_eq!(
+            warnings[0].message(),
+            "routine \"add\" takes 3 bytes from the working stack, but its signature ( a b -- c ) takes 2 bytes"
+        );
+    }
+
+    #[test]
+    fn it_warns_about_the_return_stack() {
+        let warnings = check("|0100 @keep ( a -- ) STH JMP2r");
+        assert_eq!(
+            warnings[0].message(),
+            "routine \"keep\" returns with 1 byte left on the return stack"
+        );
+    }
+
+    #[test]
+    fn it_uses_signatures_of_called_routines() {
+        let source = "|0100 @main ( -- ) #1234 emit BRK
+@emit ( a* -- ) POP2 JMP2r
+@wrapper ( a* -- ) emit JMP2r
+@tail ( a* b -- ) POP !emit";
+        assert_eq!(check(source), []);
+
+        let source = "|0100 @wrong ( a* -- ) #00 emit JMP2r
+@emit ( a* -- ) POP2 JMP2r";
+        let warnings = check(source);
+        assert_eq!(
+            warnings[0].message(),
+            "routine \"wrong\" leaves 1 byte on the working stack, but its signature ( a* -- ) leaves 0 bytes"
+        );
+    }
+
+    #[test]
+    fn it_checks_paths_that_meet_in_either_order() {
+        let message = "routine \"f\" takes 2 bytes from the working stack, but its signature ( a -- ) takes 1 byte";
+        for source in [
+            "|0100 @f ( a -- ) ?&skip !&end &skip POP #00 &end JMP2r",
+            "|0100 @f ( a -- ) ?&skip POP #00 !&end &skip &end JMP2r",
+        ] {
+            let warnings = check(source);
+            assert_eq!(warnings.len(), 1, "{source}");
+            assert_eq!(warnings[0].message(), message);
+        }
+    }
+
+    #[test]
+    fn it_follows_loops() {
+        let source = "|0100 @count ( n -- ) &loop #01 SUB DUP ?&loop POP JMP2r";
+        assert_eq!(check(source), []);
+    }
+
+    #[test]
+    fn it_reads_signatures_from_included_files() {
+        let mut resolver = MemoryResolver::new();
+        resolver.insert(PathBuf::from("lib.tal"), "@lib ( a -- ) JMP2r".to_string());
+        let options = Options {
+            file: PathBuf::from("main.tal"),
+            check_stack_effects: true,
+            ..Options::default()
+        };
+        let assembly = assemble_with_resolver("|0100 ~lib.tal", &options, &resolver).unwrap();
+        assert_eq!(assembly.warnings.len(), 1);
+        assert_eq!(assembly.warnings[0].file(), Path::new("lib.tal"));
+    }
+
+    fn infer(source: &str) -> Vec<(String, Inferred)> {
+        let options = Options {
+            file: PathBuf::from("test.tal"),
+            ..Options::default()
+        };
+        let resolver = MemoryResolver::new();
+        let assembly = assemble_with_resolver(source, &options, &resolver).unwrap();
+        let routines = infer_stack_effects(&assembly, source, &options.file, &resolver);
+        routines
+            .into_iter()
+            .map(|(routine, inferred)| (routine.name, inferred))
+            .collect()
+    }
+
+    fn inferred(takes: usize, gives: usize) -> Inferred {
+        Inferred::Effect {
+            working: effect(takes, gives),
+            returns: 0,
+        }
+    }
+
+    #[test]
+    fn it_infers_effects_through_calls() {
+        let source = "|0100 @main #1234 print-short ;emit JSR2 BRK
+@print-short SWP emit !emit
+@emit #18 DEO JMP2r";
+        let expected = [
+            ("main".to_string(), Inferred::Halts),
+            ("print-short".to_string(), inferred(2, 0)),
+            ("emit".to_string(), inferred(1, 0)),
+        ];
+        assert_eq!(infer(source), expected);
+        // Calls through a table can't be followed
+        let source = "|0100 @call-vector ;table LDA2 JSR2 JMP2r @table =call-vector";
+        assert_eq!(infer(source)[0].1, Inferred::Unknown);
+    }
+
+    #[test]
+    fn it_infers_effects_of_children() {
+        let source = "|0100 @phex SWP phex/b &b DUP #04 SFT phex/c &c #0f AND #18 DEO JMP2r";
+        assert_eq!(infer(source), [("phex".to_string(), inferred(2, 0))]);
+    }
+
+    #[test]
+    fn it_reports_mismatched_branches() {
+        let source = "|0100 @maybe-pop ?{ POP JMP2r } JMP2r";
+        assert_eq!(infer(source)[0].1, Inferred::Mismatched);
+        let source = "|0100 @drop-either ?{ POP JMP2r } POP2 JMP2r";
+        assert_eq!(infer(source)[0].1, Inferred::Mismatched);
+    }
+
+    #[test]
+    fn it_infers_paths_that_meet_in_either_order() {
+        for source in [
+            "|0100 @f ?&skip !&end &skip POP #00 &end JMP2r",
+            "|0100 @f ?&skip POP #00 !&end &skip &end JMP2r",
+        ] {
+            assert_eq!(infer(source)[0].1, inferred(2, 1), "{source}");
+        }
+    }
+
+    #[test]
+    fn it_follows_flag_jumps() {
+        let source = "|0100 @max GTHk JMP SWP POP JMP2r";
+        assert_eq!(infer(source)[0].1, inferred(2, 1));
+    }
+
+    #[test]
+    fn it_treats_paths_that_take_more_as_giving_back() {
+        let source = "|0100 @f DUP ?{ POP2 #00 JMP2r } NIP JMP2r";
+        assert_eq!(infer(source)[0].1, inferred(2, 1));
+    }
+
+    #[test]
+    fn it_leaves_recursion_unknown() {
+        let source = "|0100 @countdown DUP ?{ POP JMP2r } #01 SUB countdown JMP2r";
+        assert_eq!(infer(source)[0].1, Inferred::Unknown);
+        // Jumping back to the start is a loop though
+        let source = "|0100 @countdown #01 SUB DUP ?countdown POP JMP2r";
+        assert_eq!(infer(source)[0].1, inferred(1, 0));
+    }
+
+    #[test]
+    fn it_infers_return_stack_effects() {
+        let inferred = infer("|0100 @stash STH JMP2r")[0].1;
+        let expected = Inferred::Effect {
+            working: effect(1, 0),
+            returns: 1,
+        };
+        assert_eq!(inferred, expected);
+        assert_eq!(
+            inferred.to_string(),
+            "( 1 -- 0 ) leaving 1 on the return stack"
+        );
+        assert_eq!(
+            Inferred::Mismatched.to_string(),
+            "unknown, paths return with different depths"
+        );
+    }
+}
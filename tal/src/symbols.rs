@@ -0,0 +1,141 @@
+use std::io::Write;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Symbol {
+    pub address: u16,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Default)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols { symbols: vec![] }
+    }
+
+    pub fn push(&mut self, name: String, address: u16) {
+        self.symbols.push(Symbol { address, name });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    // Symbols sorted by address, labels sharing an address stay in definition order
+    pub fn sorted(&self) -> Vec<&Symbol> {
+        let mut symbols: Vec<&Symbol> = self.symbols.iter().collect();
+        symbols.sort_by_key(|symbol| symbol.address);
+        symbols
+    }
+
+    // The uxnasm format: a big-endian address followed by a NUL-terminated name
+    pub fn write_binary(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        for symbol in self.sorted() {
+            writer.write_all(&symbol.address.to_be_bytes())?;
+            writer.write_all(symbol.name.as_bytes())?;
+            writer.write_all(&[0])?;
+        }
+        Ok(())
+    }
+
+    // Reads symbols written by write_binary or uxnasm
+    pub fn read_binary(bytes: &[u8]) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            if rest.len() < 2 {
+                return Err("symbol file ends in the middle of an address".to_string());
+            }
+            let address = u16::from_be_bytes([rest[0], rest[1]]);
+            let length = match rest[2..].iter().position(|byte| *byte == 0) {
+                Some(length) => length,
+                None => return Err(format!("symbol at {address:04x} has no NUL after its name")),
+            };
+            let name = match String::from_utf8(rest[2..2 + length].to_vec()) {
+                Ok(name) => name,
+                Err(_) => return Err(format!("symbol at {address:04x} is not valid UTF-8")),
+            };
+            symbols.push(name, address);
+            rest = &rest[2 + length + 1..];
+        }
+        Ok(symbols)
+    }
+
+    pub fn write_text(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        for symbol in self.sorted() {
+            writeln!(writer, "{:04x} {}", symbol.address, symbol.name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> Symbols {
+        let mut symbols = Symbols::new();
+        symbols.push("on-reset".to_string(), 0x0100);
+        symbols.push("System".to_string(), 0x0000);
+        symbols.push("System/vector".to_string(), 0x0000);
+        symbols
+    }
+
+    #[test]
+    fn it_writes_binary() {
+        let mut output = vec![];
+        symbols().write_binary(&mut output).unwrap();
+
+        let mut expected = vec![0x00, 0x00];
+        expected.extend_from_slice(b"System\0");
+        expected.extend_from_slice(&[0x00, 0x00]);
+        expected.extend_from_slice(b"System/vector\0");
+        expected.extend_from_slice(&[0x01, 0x00]);
+        expected.extend_from_slice(b"on-reset\0");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn it_reads_binary() {
+        let mut output = vec![];
+        symbols().write_binary(&mut output).unwrap();
+
+        let symbols = Symbols::read_binary(&output).unwrap();
+        let names: Vec<(u16, &str)> = symbols
+            .iter()
+            .map(|symbol| (symbol.address, symbol.name.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (0x0000, "System"),
+                (0x0000, "System/vector"),
+                (0x0100, "on-reset")
+            ]
+        );
+    }
+
+    #[test]
+    fn reading_truncated_binary_fails() {
+        assert_eq!(
+            Symbols::read_binary(b"\x01\x00on-reset\0\x01"),
+            Err("symbol file ends in the middle of an address".to_string())
+        );
+        assert_eq!(
+            Symbols::read_binary(b"\x01\x00on-reset"),
+            Err("symbol at 0100 has no NUL after its name".to_string())
+        );
+    }
+
+    #[test]
+    fn it_writes_text() {
+        let mut output = vec![];
+        symbols().write_text(&mut output).unwrap();
+
+        let expected = "0000 System\n0000 System/vector\n0100 on-reset\n";
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::chunker::Chunk;
 use crate::opcode::Opcode;
+use std::str::FromStr;
 
 #[derive(Debug, PartialEq)]
 pub enum TokenType {
@@ -11,6 +12,8 @@ pub enum TokenType {
     LiteralShort(u16),
     PaddingAbsolute(u16),
     PaddingRelative(u16),
+    PaddingAbsoluteLabel(String, bool),
+    PaddingRelativeLabel(String, bool),
     RawAscii(String),
     LabelParent(String),
     LabelChild(String),
@@ -22,6 +25,10 @@ pub enum TokenType {
     AddressRawAbsoluteByte(String, bool),
     AddressRawAbsoluteShort(String, bool),
     AddressRawRelative(String, bool),
+    LambdaInstant,
+    LambdaConditional,
+    LambdaUnconditional,
+    LambdaEnd,
 }
 
 impl TokenType {
@@ -42,11 +49,27 @@ impl TokenType {
             return Ok(TokenType::RawShort(short));
         }
 
+        // Lambdas
+
+        match chunk.value.as_str() {
+            "{" => return Ok(TokenType::LambdaInstant),
+            "?{" => return Ok(TokenType::LambdaConditional),
+            "!{" => return Ok(TokenType::LambdaUnconditional),
+            "}" => return Ok(TokenType::LambdaEnd),
+            _ => {}
+        }
+
         // Match first character
 
-        let token_type = match &chunk.value.as_str()[0..1] {
+        // get returns None when the first character is longer than a byte
+        let token_type = match chunk.value.get(0..1).unwrap_or_default() {
             "|" => {
                 let number = &chunk.value[1..];
+                // Anything that isn't hex is the name of a label to move to
+                if !number.is_empty() && !number.chars().all(|chr| chr.is_ascii_hexdigit()) {
+                    let (name, child) = parse_name(number);
+                    return Ok(TokenType::PaddingAbsoluteLabel(name.to_string(), child));
+                }
                 if let Ok(short) = parse_short(number) {
                     TokenType::PaddingAbsolute(short)
                 } else if let Ok(byte) = parse_byte(number) {
@@ -58,6 +81,11 @@ impl TokenType {
 
             "$" => {
                 let number = &chunk.value[1..];
+                // Anything that isn't hex is the name of a label whose address is the size
+                if !number.is_empty() && !number.chars().all(|chr| chr.is_ascii_hexdigit()) {
+                    let (name, child) = parse_name(number);
+                    return Ok(TokenType::PaddingRelativeLabel(name.to_string(), child));
+                }
                 let number = if number.len() & 1 == 1 {
                     "0".to_string() + number
                 } else {
@@ -166,7 +194,7 @@ pub struct Token {
     pub chunk: Chunk,
 }
 
-fn parse_byte(s: &str) -> Result<u8, String> {
+pub fn parse_byte(s: &str) -> Result<u8, String> {
     if s.len() != 2 {
         Err("not 2 bytes long".to_string())
     } else {
@@ -177,7 +205,7 @@ fn parse_byte(s: &str) -> Result<u8, String> {
     }
 }
 
-fn parse_short(s: &str) -> Result<u16, String> {
+pub fn parse_short(s: &str) -> Result<u16, String> {
     if s.len() != 4 {
         Err("not 4 bytes long".to_string())
     } else {
@@ -192,12 +220,9 @@ fn parse_short(s: &str) -> Result<u16, String> {
 }
 
 fn parse_name(s: &str) -> (&str, bool) {
-    if s.is_empty() {
-        ("", false)
-    } else if &s[0..1] == "&" {
-        (&s[1..], true)
-    } else {
-        (s, false)
+    match s.strip_prefix('&') {
+        Some(name) => (name, true),
+        None => (s, false),
     }
 }
 
@@ -218,7 +243,7 @@ mod tests {
 
     macro_rules! assert_match {
         ( $a:expr, $b:expr ) => {{
-            let chunk = Chunk::new(String::from($a), 0, 0);
+            let chunk = Chunk::new(String::from($a), 0, 0, 0);
             let result = Token::from_chunk(&chunk);
             assert!(result.is_ok());
             let tt = result.unwrap().token_type;
@@ -228,7 +253,7 @@ mod tests {
 
     macro_rules! assert_err {
         ( $a:expr ) => {{
-            let chunk = Chunk::new($a.to_string(), 0, 0);
+            let chunk = Chunk::new($a.to_string(), 0, 0, 0);
             let result = Token::from_chunk(&chunk);
             assert!(result.is_err());
         }};
@@ -244,6 +269,15 @@ mod tests {
         assert_match!("|11", TokenType::PaddingAbsolute(0x0011));
     }
 
+    #[test]
+    fn multi_byte_names_work() {
+        assert_match!("λ→", TokenType::Instant(String::from("λ→")));
+        assert_match!(
+            ";é",
+            TokenType::AddressLiteralAbsoluteShort(String::from("é"), false)
+        );
+    }
+
     #[test]
     fn ascii_works() {
         assert_match!("\"foobar", TokenType::RawAscii("foobar".to_string()));
@@ -293,6 +327,33 @@ mod tests {
         assert_err!("$");
     }
 
+    #[test]
+    fn padding_to_labels_works() {
+        assert_match!(
+            "|on-reset",
+            TokenType::PaddingAbsoluteLabel("on-reset".to_string(), false)
+        );
+        assert_match!(
+            "|&child",
+            TokenType::PaddingAbsoluteLabel("child".to_string(), true)
+        );
+        assert_match!(
+            "$Point/size",
+            TokenType::PaddingRelativeLabel("Point/size".to_string(), false)
+        );
+        assert_match!(
+            "$&size",
+            TokenType::PaddingRelativeLabel("size".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn padding_absolute_fails() {
+        assert_err!("|");
+        assert_err!("|100");
+        assert_err!("|0");
+    }
+
     #[test]
     fn raw_byte_works() {
         assert_match!("AB", TokenType::RawByte(0xab));
@@ -300,7 +361,7 @@ mod tests {
 
     #[test]
     fn raw_byte_fails() {
-        let chunk = Chunk::new("A".to_string(), 0, 0);
+        let chunk = Chunk::new("A".to_string(), 0, 0, 0);
         let result = Token::from_chunk(&chunk);
         assert_eq!(
             result.unwrap().token_type,
@@ -315,7 +376,7 @@ mod tests {
 
     #[test]
     fn raw_short_fails() {
-        let chunk = Chunk::new("ABC".to_string(), 0, 0);
+        let chunk = Chunk::new("ABC".to_string(), 0, 0, 0);
         let result = Token::from_chunk(&chunk);
         assert_eq!(
             result.unwrap().token_type,
@@ -423,4 +484,16 @@ mod tests {
             TokenType::ImmediateUnconditional("".to_string(), true)
         );
     }
+
+    #[test]
+    fn lambdas_work() {
+        assert_match!("{", TokenType::LambdaInstant);
+        assert_match!("?{", TokenType::LambdaConditional);
+        assert_match!("!{", TokenType::LambdaUnconditional);
+        assert_match!("}", TokenType::LambdaEnd);
+        assert_match!(
+            "?{foo",
+            TokenType::ImmediateConditional("{foo".to_string(), false)
+        );
+    }
 }
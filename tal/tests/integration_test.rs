@@ -31,6 +31,12 @@ impl Rom {
     fn get_bytes(&self) -> &[u8] {
         &self.rom
     }
+
+    fn trim(&mut self) {
+        while self.rom.last() == Some(&0) {
+            self.rom.pop();
+        }
+    }
 }
 
 impl Debug for Rom {
@@ -99,14 +105,19 @@ fn expect_eq_rom(left: Rom, right: Rom) -> Result<(), String> {
     Ok(())
 }
 
-fn expect_eq_files(left: PathBuf, right: PathBuf) -> Result<(), String> {
+// trim drops trailing zeros from right before comparing, the ROMs here were assembled by a
+// version of uxnasm that kept them
+fn expect_eq_files(left: PathBuf, right: PathBuf, trim: bool) -> Result<(), String> {
     let _left = Rom::from_file(left.clone());
     assert!(_left.is_ok(), "{left:?} should be OK");
     let _left = _left.unwrap();
 
     let _right = Rom::from_file(right.clone());
     assert!(_right.is_ok(), "{right:?} should be OK");
-    let _right = _right.unwrap();
+    let mut _right = _right.unwrap();
+    if trim {
+        _right.trim();
+    }
 
     expect_eq_rom(_left, _right)
 }
@@ -120,19 +131,132 @@ fn expect_successful_assembly(cwd: &PathBuf, tal: PathBuf, rom: PathBuf) -> Resu
     println!("tal {} {}", relative(cwd, &tal), relative(cwd, &rom));
     let tmp = temp_dir().join("tal-test.rom");
 
+    for trim in [true, false] {
+        let mut command = Command::new(root_dir().join("target/debug/tal"));
+        if !trim {
+            command.arg("--no-trim");
+        }
+        let result = command
+            .arg(tal.clone())
+            .arg(tmp.clone())
+            .current_dir(cwd)
+            .output();
+        expect(result.is_ok(), "Command failed".to_string())?;
+        let cmd = result.unwrap();
+        expect(
+            cmd.status.success(),
+            format!("exit code: {:?}", cmd.status.code()),
+        )?;
+
+        expect_eq_files(tmp.clone(), rom.clone(), trim)?;
+    }
+
+    // Assembling also wrote tmp.sym, so this checks the labels are put back in the right places
+    expect_successful_disassembly(cwd, tmp)
+}
+
+// The disassembly has to assemble back to exactly the same bytes, trailing zeros and all. The
+// symbols in rom.sym are used when it exists
+fn expect_successful_disassembly(cwd: &PathBuf, rom: PathBuf) -> Result<(), String> {
+    println!(
+        "tal disasm {}",
+        rom.strip_prefix(cwd).unwrap_or(&rom).display()
+    );
+    let tmp_tal = temp_dir().join("tal-test-disasm.tal");
+    let tmp_rom = temp_dir().join("tal-test-disasm.rom");
+
+    let result = Command::new(root_dir().join("target/debug/tal"))
+        .arg("disasm")
+        .arg(rom.clone())
+        .arg(tmp_tal.clone())
+        .current_dir(cwd)
+        .output();
+    expect(result.is_ok(), "Command failed".to_string())?;
+    let cmd = result.unwrap();
+    expect(
+        cmd.status.success(),
+        format!("disasm exit code: {:?}", cmd.status.code()),
+    )?;
+
+    let result = Command::new(root_dir().join("target/debug/tal"))
+        .arg("--no-trim")
+        .arg(tmp_tal)
+        .arg(tmp_rom.clone())
+        .current_dir(cwd)
+        .output();
+    expect(result.is_ok(), "Command failed".to_string())?;
+    let cmd = result.unwrap();
+    expect(
+        cmd.status.success(),
+        format!("reassembly exit code: {:?}", cmd.status.code()),
+    )?;
+
+    expect_eq_files(tmp_rom, rom, false)
+}
+
+// The formatted source has to assemble to the same ROM, and be left alone by formatting again
+fn expect_successful_format(cwd: &PathBuf, tal: PathBuf, rom: PathBuf) -> Result<(), String> {
+    println!("tal fmt {}", relative(cwd, &tal));
+    let tmp_tal = temp_dir().join("tal-test-fmt.tal");
+    let tmp_rom = temp_dir().join("tal-test-fmt.rom");
+
     let result = Command::new(root_dir().join("target/debug/tal"))
+        .arg("fmt")
         .arg(tal)
-        .arg(tmp.clone())
+        .arg(tmp_tal.clone())
+        .current_dir(cwd)
+        .output();
+    expect(result.is_ok(), "Command failed".to_string())?;
+    let cmd = result.unwrap();
+    expect(
+        cmd.status.success(),
+        format!("fmt exit code: {:?}", cmd.status.code()),
+    )?;
+
+    let result = Command::new(root_dir().join("target/debug/tal"))
+        .arg("fmt")
+        .arg("--check")
+        .arg(tmp_tal.clone())
         .current_dir(cwd)
         .output();
     expect(result.is_ok(), "Command failed".to_string())?;
     let cmd = result.unwrap();
     expect(
         cmd.status.success(),
-        format!("exit code: {:?}", cmd.status.code()),
+        "formatting again changed the source".to_string(),
     )?;
 
-    expect_eq_files(tmp, rom)
+    let result = Command::new(root_dir().join("target/debug/tal"))
+        .arg(tmp_tal)
+        .arg(tmp_rom.clone())
+        .current_dir(cwd)
+        .output();
+    expect(result.is_ok(), "Command failed".to_string())?;
+    let cmd = result.unwrap();
+    expect(
+        cmd.status.success(),
+        format!("formatted exit code: {:?}", cmd.status.code()),
+    )?;
+
+    expect_eq_files(tmp_rom, rom, true)
+}
+
+// Analysis follows every path through whatever's in the ROM, so it has to cope with anything
+fn expect_successful_analysis(cwd: &PathBuf, tal: PathBuf) -> Result<(), String> {
+    println!("tal analyze --stack-effects {}", relative(cwd, &tal));
+
+    let result = Command::new(root_dir().join("target/debug/tal"))
+        .arg("analyze")
+        .arg("--stack-effects")
+        .arg(tal)
+        .current_dir(cwd)
+        .output();
+    expect(result.is_ok(), "Command failed".to_string())?;
+    let cmd = result.unwrap();
+    expect(
+        cmd.status.success(),
+        format!("analyze exit code: {:?}", cmd.status.code()),
+    )
 }
 
 fn expect_unsuccessful_assembly(cwd: &PathBuf, tal: PathBuf) -> Result<(), String> {
@@ -188,6 +312,7 @@ fn find_tal_files(path: &Path) -> (Vec<PathBuf>, Vec<PathBuf>) {
 }
 
 #[test]
+#[allow(clippy::unnecessary_unwrap)]
 fn it_works() {
     let path = root_dir().join("tal/tests/roms");
     let (tal_files_with_roms, tal_files_without_roms) = find_tal_files(&path);
@@ -198,7 +323,10 @@ fn it_works() {
     for tal_path in tal_files_with_roms {
         let relative_path = relative(&path, &tal_path);
         let rom_path = tal_path.with_extension("rom");
-        let result = expect_successful_assembly(&path, tal_path, rom_path.clone());
+        let result = expect_successful_assembly(&path, tal_path.clone(), rom_path.clone())
+            .and_then(|_| expect_successful_disassembly(&path, rom_path.clone()))
+            .and_then(|_| expect_successful_format(&path, tal_path.clone(), rom_path.clone()))
+            .and_then(|_| expect_successful_analysis(&path, tal_path));
         results_expect_successful.push((relative_path, result));
     }
 